@@ -0,0 +1,11 @@
+use adventofcode2017_clp::{day_18, day_23};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+fn duet(c: &mut Criterion) {
+    c.bench_function("day_18_part_1", |b| b.iter(|| black_box(day_18::part_1())));
+    c.bench_function("day_18_part_2", |b| b.iter(|| black_box(day_18::part_2())));
+    c.bench_function("day_23_part_1", |b| b.iter(|| black_box(day_23::part_1())));
+}
+
+criterion_group!(benches, duet);
+criterion_main!(benches);
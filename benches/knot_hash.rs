@@ -0,0 +1,12 @@
+use adventofcode2017_clp::{day_10, day_14};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+fn knot_hash(c: &mut Criterion) {
+    c.bench_function("day_10_part_2", |b| b.iter(|| black_box(day_10::part_2())));
+
+    // Dominated by `Disk::sda`, which computes 128 knot hashes.
+    c.bench_function("day_14_part_1", |b| b.iter(|| black_box(day_14::part_1())));
+}
+
+criterion_group!(benches, knot_hash);
+criterion_main!(benches);
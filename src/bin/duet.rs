@@ -0,0 +1,7 @@
+use std::{env, io};
+
+use adventofcode2017_clp::tools::duet::run;
+
+fn main() -> Result<(), anyhow::Error> {
+    run(env::args().skip(1), io::stdin().lock(), io::stdout().lock())
+}
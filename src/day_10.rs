@@ -4,9 +4,16 @@ use crate::helpers::knot_hash::KnotHash;
 use crate::input::day_10::INPUT;
 
 pub fn part_1() -> usize {
-    KnotHash::sparse_hash(part_1_lengths(), 1)
-        .into_iter()
-        .map(|n| n as usize)
+    KnotHash::builder()
+        .rounds(1)
+        .suffix([])
+        .block_size(1)
+        .build()
+        .update(&part_1_lengths())
+        .finalize()
+        .dense()
+        .iter()
+        .map(|&n| n as usize)
         .take(2)
         .reduce(Mul::mul)
         .unwrap()
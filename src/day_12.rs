@@ -1,8 +1,9 @@
 use std::collections::{HashMap, HashSet};
-use std::str::FromStr;
+use std::sync::OnceLock;
 
-use itertools::Itertools;
+use regex::Regex;
 
+use crate::helpers::regex::CapturesHelper;
 use crate::input::day_12::INPUT;
 
 pub fn part_1() -> usize {
@@ -21,14 +22,18 @@ struct Program {
 
 impl<'a> From<&'a str> for Program {
     fn from(value: &'a str) -> Self {
-        let (id, neighbours) = value.split(" <-> ").collect_tuple().unwrap();
+        static REGEX: OnceLock<Regex> = OnceLock::new();
+        static NEIGHBOUR_REGEX: OnceLock<Regex> = OnceLock::new();
+        let re = REGEX
+            .get_or_init(|| Regex::new(r"^(?<id>\d+) <-> (?<neighbours>\d+(?:, \d+)*)$").unwrap());
+        let neighbour_re = NEIGHBOUR_REGEX.get_or_init(|| Regex::new(r"\d+").unwrap());
+
+        let captures = re
+            .captures(value)
+            .unwrap_or_else(|| panic!("invalid program: {value}"));
         Self {
-            id: id.parse().unwrap(),
-            neighbours: neighbours
-                .split(", ")
-                .map(FromStr::from_str)
-                .collect::<Result<_, _>>()
-                .unwrap(),
+            id: captures.ez_get("id"),
+            neighbours: captures.get_all("neighbours", neighbour_re).unwrap(),
         }
     }
 }
@@ -28,10 +28,7 @@ impl Disk {
     }
 
     pub fn used_count(&self) -> u32 {
-        self.hashes
-            .iter()
-            .map(|h| h.dense().iter().map(|n| n.count_ones()).sum::<u32>())
-            .sum()
+        self.hashes.iter().map(KnotHash::count_ones).sum()
     }
 
     pub fn regions_count(&self) -> usize {
@@ -64,11 +61,7 @@ impl Disk {
 
         self.hashes
             .get(pt.y as usize)
-            .and_then(|h| {
-                h.dense()
-                    .get((pt.x / 8) as usize)
-                    .map(|&n| n & (1 << (7 - pt.x % 8)) != 0)
-            })
+            .and_then(|h| h.bits().nth(pt.x as usize))
             .unwrap_or(false)
     }
 
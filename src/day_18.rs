@@ -1,118 +1,75 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::str::FromStr;
+use std::fmt;
+use std::hash::Hasher;
 
 use anyhow::{anyhow, Context};
 
-use crate::helpers::duet::{read_instructions, read_register, read_value, Queue, Registers, Value};
+use crate::helpers::duet::compiled::{self, Compile, CompiledInstruction};
+use crate::helpers::duet::decompiler::{Decompile, Expr, Statement};
+use crate::helpers::duet::device::{Device, SoundCard};
+use crate::helpers::duet::history::Reversible;
+use crate::helpers::duet::lint::{Io, Lint};
+use crate::helpers::duet::network::{NetworkBuilder, NetworkContext, Ports};
+use crate::helpers::duet::snapshot::SnapshotContext;
+use crate::helpers::duet::{
+    read_register, read_value, InstructionResult, InstructionSet, Jump, JumpCondition, Operation,
+    ParseInstruction, Program, Register, RegisterNames, Registers, Value, Vm,
+};
 use crate::input::day_18::INPUT;
 
 pub fn part_1() -> i64 {
-    let program: Program = INPUT.parse().unwrap();
+    let program = INPUT.parse().unwrap();
     let mut interpreter = DuetInterpreter::for_part_1(program);
+    interpreter.compile();
 
     loop {
-        if let Some(n) = interpreter.execute_next().unwrap().received() {
-            return n;
+        match interpreter.execute_next().unwrap() {
+            InstructionResult::Received(n) => return n,
+            InstructionResult::Exited => panic!("program exited before recovering a sound"),
+            _ => {},
         }
     }
 }
 
 pub fn part_2() -> usize {
-    let program: Program = INPUT.parse().unwrap();
-
-    let program_0_queue = Rc::new(RefCell::new(Queue::default()));
-    let program_1_queue = Rc::new(RefCell::new(Queue::default()));
-
-    let mut interpreters = [
-        DuetInterpreter::for_part_2(
-            program.clone(),
-            0,
-            Rc::clone(&program_1_queue),
-            Rc::clone(&program_0_queue),
-        ),
-        DuetInterpreter::for_part_2(
-            program.clone(),
-            1,
-            Rc::clone(&program_0_queue),
-            Rc::clone(&program_1_queue),
-        ),
-    ];
-
-    let mut interpreter = 0usize;
-    let mut wait_count = 0;
-    loop {
-        let next_interpreter = &mut interpreters[interpreter];
-        loop {
-            match next_interpreter.execute_next().unwrap() {
-                InstructionResult::Waiting => break,
-                _ => wait_count = 0,
-            }
-        }
+    let program: Program<Instruction> = INPUT.parse().unwrap();
+    let mut network = NetworkBuilder::new(2)
+        .build(|ports| {
+            let mut interpreter = DuetInterpreter::networked(program.clone(), ports);
+            interpreter.compile();
+            interpreter
+        })
+        .unwrap();
 
-        wait_count += 1;
-        if wait_count == 2 {
-            return interpreters[1].send_count();
-        }
-        interpreter = (interpreter + 1) % interpreters.len();
-    }
+    network.run().unwrap();
+    network.sent(1)
 }
 
-#[derive(Debug, Copy, Clone)]
-enum Instruction {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Instruction {
     Snd(Value),
-    Set(char, Value),
-    Add(char, Value),
-    Mul(char, Value),
-    Mod(char, Value),
-    Rcv(char),
+    Set(Register, Value),
+    Add(Register, Value),
+    Mul(Register, Value),
+    Mod(Register, Value),
+    Rcv(Register),
     Jgz(Value, Value),
 }
 
-impl Instruction {
-    pub fn execute(
+impl InstructionSet for Instruction {
+    type Context = DuetContext;
+
+    fn execute(
         &self,
         registers: &mut Registers,
-        send_count: &mut usize,
-        send_queue: Rc<RefCell<Queue>>,
-        rcv_queue: Rc<RefCell<Queue>>,
-        part_1: bool,
+        context: &mut DuetContext,
     ) -> Result<InstructionResult, anyhow::Error> {
         match self {
-            Self::Snd(value) => {
-                *send_count += 1;
-                send_queue.borrow_mut().push(value.get(registers));
-            },
-            Self::Set(register, value) => registers.set(*register, value.get(registers)),
-            Self::Add(register, value) => {
-                registers.set(*register, registers.get(*register) + value.get(registers))
-            },
-            Self::Mul(register, value) => {
-                registers.set(*register, registers.get(*register) * value.get(registers))
-            },
-            Self::Mod(register, value) => {
-                registers.set(*register, registers.get(*register).rem_euclid(value.get(registers)))
-            },
-            Self::Rcv(register) => {
-                if part_1 {
-                    if registers.get(*register) != 0 {
-                        return Ok(InstructionResult::Received(
-                            rcv_queue
-                                .borrow_mut()
-                                .pop_last()
-                                .with_context(|| "no sound played")?,
-                        ));
-                    }
-                } else {
-                    return Ok(match rcv_queue.borrow_mut().pop() {
-                        Some(n) => {
-                            registers.set(*register, n);
-                            InstructionResult::Received(n)
-                        },
-                        None => InstructionResult::Waiting,
-                    });
-                }
-            },
+            Self::Snd(value) => return context.device.send(value.get_exact(registers)?),
+            Self::Set(register, value) => registers.apply(register, Operation::Set, value)?,
+            Self::Add(register, value) => registers.apply(register, Operation::Add, value)?,
+            Self::Mul(register, value) => registers.apply(register, Operation::Mul, value)?,
+            Self::Mod(register, value) => registers.apply(register, Operation::Mod, value)?,
+            Self::Rcv(register) => return context.device.receive(registers, register),
             Self::Jgz(value, jmp_offset) => {
                 if value.get(registers) > 0 {
                     return Ok(InstructionResult::JmpOffset(jmp_offset.get(registers)));
@@ -122,134 +79,215 @@ impl Instruction {
 
         Ok(InstructionResult::Unit)
     }
-}
 
-impl FromStr for Instruction {
-    type Err = anyhow::Error;
+    fn jump(&self) -> Option<Jump<'_>> {
+        match self {
+            Self::Jgz(value, offset) => {
+                Some(Jump { value, condition: JumpCondition::Positive, offset })
+            },
+            _ => None,
+        }
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split_whitespace();
-        let opcode = parts.next().with_context(|| "empty instruction")?;
+    fn hash_context<H>(context: &DuetContext, state: &mut H)
+    where
+        H: Hasher,
+    {
+        context.device.hash_state(state);
+    }
+}
 
-        match opcode {
-            "snd" => Ok(Self::Snd(read_value(&mut parts)?)),
-            "set" => Ok(Self::Set(read_register(&mut parts)?, read_value(&mut parts)?)),
-            "add" => Ok(Self::Add(read_register(&mut parts)?, read_value(&mut parts)?)),
-            "mul" => Ok(Self::Mul(read_register(&mut parts)?, read_value(&mut parts)?)),
-            "mod" => Ok(Self::Mod(read_register(&mut parts)?, read_value(&mut parts)?)),
-            "rcv" => Ok(Self::Rcv(read_register(&mut parts)?)),
-            "jgz" => Ok(Self::Jgz(read_value(&mut parts)?, read_value(&mut parts)?)),
-            opcode => Err(anyhow!("invalid opcode: {opcode}")),
+impl Compile for Instruction {
+    fn compile(&self) -> CompiledInstruction<DuetContext> {
+        match self {
+            Self::Set(register, value) => compiled::apply(register, Operation::Set, value, |_| {}),
+            Self::Add(register, value) => compiled::apply(register, Operation::Add, value, |_| {}),
+            Self::Mul(register, value) => compiled::apply(register, Operation::Mul, value, |_| {}),
+            Self::Mod(register, value) => compiled::apply(register, Operation::Mod, value, |_| {}),
+            Self::Jgz(value, offset) => {
+                compiled::jump(value, JumpCondition::Positive, offset, |_| {})
+            },
+            Self::Snd(value) => {
+                let value = value.clone();
+                Box::new(move |registers, context| context.device.send(value.get_exact(registers)?))
+            },
+            Self::Rcv(register) => {
+                let register = register.clone();
+                Box::new(move |registers, context| context.device.receive(registers, &register))
+            },
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum InstructionResult {
-    Unit,
-    JmpOffset(i64),
-    Received(i64),
-    Waiting,
+impl Reversible for Instruction {
+    fn undo(
+        &self,
+        context: &mut DuetContext,
+        result: &InstructionResult,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Snd(_) | Self::Rcv(_) => context.device.undo(result),
+            _ => Ok(()),
+        }
+    }
 }
 
-impl InstructionResult {
-    pub fn jmp_offset(&self) -> i64 {
+impl Lint for Instruction {
+    fn reads(&self) -> Vec<&Register> {
+        let (register, values) = match self {
+            Self::Snd(value) => (None, vec![value]),
+            Self::Set(_, value) => (None, vec![value]),
+            Self::Add(register, value)
+            | Self::Mul(register, value)
+            | Self::Mod(register, value) => (Some(register), vec![value]),
+            // With a sound card, `rcv` also reads its register, but only to check it isn't 0.
+            Self::Rcv(_) => (None, vec![]),
+            Self::Jgz(value, offset) => (None, vec![value, offset]),
+        };
+        register
+            .into_iter()
+            .chain(values.into_iter().filter_map(Value::register))
+            .collect()
+    }
+
+    fn writes(&self) -> Option<&Register> {
         match self {
-            Self::JmpOffset(offset) => *offset,
-            Self::Waiting => 0,
-            _ => 1,
+            Self::Set(register, _)
+            | Self::Add(register, _)
+            | Self::Mul(register, _)
+            | Self::Mod(register, _)
+            | Self::Rcv(register) => Some(register),
+            Self::Snd(_) | Self::Jgz(..) => None,
         }
     }
 
-    pub fn received(&self) -> Option<i64> {
+    fn divisor(&self) -> Option<&Value> {
         match self {
-            Self::Received(n) => Some(*n),
+            Self::Mod(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn io(&self) -> Option<Io> {
+        match self {
+            Self::Snd(_) => Some(Io::Send),
+            Self::Rcv(_) => Some(Io::Receive),
             _ => None,
         }
     }
 }
 
-#[derive(Debug, Clone)]
-struct Program(Vec<Instruction>);
+/// What `rcv` does depends on the device, so it is decompiled to a call to `receive`.
+impl Decompile for Instruction {
+    fn statement(&self) -> Option<Statement> {
+        Some(match self {
+            Self::Snd(value) => Statement::Call("send", vec![value.into()]),
+            Self::Set(register, value) => Statement::apply(register, Operation::Set, value),
+            Self::Add(register, value) => Statement::apply(register, Operation::Add, value),
+            Self::Mul(register, value) => Statement::apply(register, Operation::Mul, value),
+            Self::Mod(register, value) => Statement::apply(register, Operation::Mod, value),
+            Self::Rcv(register) => {
+                Statement::Call("receive", vec![Expr::Register(register.clone())])
+            },
+            Self::Jgz(..) => return None,
+        })
+    }
+}
 
-impl Program {
-    pub fn execute(
-        &self,
-        ip: i64,
-        registers: &mut Registers,
-        send_count: &mut usize,
-        send_queue: Rc<RefCell<Queue>>,
-        rcv_queue: Rc<RefCell<Queue>>,
-        part_1: bool,
-    ) -> Result<InstructionResult, anyhow::Error> {
-        self.0
-            .get(ip as usize)
-            .with_context(|| format!("invalid instruction pointer: {ip}"))?
-            .execute(registers, send_count, send_queue, rcv_queue, part_1)
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Snd(value) => write!(f, "snd {value}"),
+            Self::Set(register, value) => write!(f, "set {register} {value}"),
+            Self::Add(register, value) => write!(f, "add {register} {value}"),
+            Self::Mul(register, value) => write!(f, "mul {register} {value}"),
+            Self::Mod(register, value) => write!(f, "mod {register} {value}"),
+            Self::Rcv(register) => write!(f, "rcv {register}"),
+            Self::Jgz(value, jmp_offset) => write!(f, "jgz {value} {jmp_offset}"),
+        }
     }
 }
 
-impl FromStr for Program {
-    type Err = anyhow::Error;
+impl ParseInstruction for Instruction {
+    fn parse(s: &str, names: &mut RegisterNames) -> Result<Self, anyhow::Error> {
+        let mut parts = s.split_whitespace();
+        let opcode = parts.next().with_context(|| "empty instruction")?;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(read_instructions(s)?))
+        match opcode {
+            "snd" => Ok(Self::Snd(read_value(&mut parts, names)?)),
+            "set" => {
+                Ok(Self::Set(read_register(&mut parts, names)?, read_value(&mut parts, names)?))
+            },
+            "add" => {
+                Ok(Self::Add(read_register(&mut parts, names)?, read_value(&mut parts, names)?))
+            },
+            "mul" => {
+                Ok(Self::Mul(read_register(&mut parts, names)?, read_value(&mut parts, names)?))
+            },
+            "mod" => {
+                Ok(Self::Mod(read_register(&mut parts, names)?, read_value(&mut parts, names)?))
+            },
+            "rcv" => Ok(Self::Rcv(read_register(&mut parts, names)?)),
+            "jgz" => Ok(Self::Jgz(read_value(&mut parts, names)?, read_value(&mut parts, names)?)),
+            opcode => Err(anyhow!("invalid opcode: {opcode}")),
+        }
     }
 }
 
+/// Context of day 18's instructions: the [`Device`] that `snd` and `rcv` communicate with.
 #[derive(Debug)]
-struct DuetInterpreter {
-    program: Program,
-    registers: Registers,
-    ip: i64,
-    send_count: usize,
-    send_queue: Rc<RefCell<Queue>>,
-    rcv_queue: Rc<RefCell<Queue>>,
-    part_1: bool,
+pub(crate) struct DuetContext {
+    device: Box<dyn Device>,
 }
 
-impl DuetInterpreter {
-    pub fn for_part_1(program: Program) -> Self {
-        let send_queue = Rc::new(RefCell::new(Queue::default()));
-        let rcv_queue = Rc::clone(&send_queue);
-
-        Self {
-            program,
-            registers: Registers::default(),
-            ip: 0,
-            send_count: 0,
-            send_queue,
-            rcv_queue,
-            part_1: true,
-        }
+/// The device's state is saved as is.
+impl SnapshotContext for DuetContext {
+    type Snapshot = serde_json::Value;
+
+    fn snapshot(&self) -> serde_json::Value {
+        self.device.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: serde_json::Value) -> Result<(), anyhow::Error> {
+        self.device.restore(snapshot)
     }
+}
 
-    pub fn for_part_2(
-        program: Program,
-        id: i64,
-        send_queue: Rc<RefCell<Queue>>,
-        rcv_queue: Rc<RefCell<Queue>>,
-    ) -> Self {
-        let mut registers = Registers::default();
-        registers.set('p', id);
+impl NetworkContext for DuetContext {
+    fn ports(&self) -> Option<&Ports> {
+        self.device.ports()
+    }
+}
 
-        Self { program, registers, ip: 0, send_count: 0, send_queue, rcv_queue, part_1: false }
+impl fmt::Display for DuetContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.device.fmt(f)
+    }
+}
+
+pub(crate) type DuetInterpreter = Vm<Instruction>;
+
+impl DuetInterpreter {
+    /// Creates an interpreter whose `snd` and `rcv` instructions communicate with `device`.
+    pub fn with_device<D>(program: Program<Instruction>, device: D) -> Self
+    where
+        D: Device + 'static,
+    {
+        Self::new(program, DuetContext { device: Box::new(device) })
     }
 
-    pub fn execute_next(&mut self) -> Result<InstructionResult, anyhow::Error> {
-        let result = self.program.execute(
-            self.ip,
-            &mut self.registers,
-            &mut self.send_count,
-            Rc::clone(&self.send_queue),
-            Rc::clone(&self.rcv_queue),
-            self.part_1,
-        )?;
-        self.ip += result.jmp_offset();
-        Ok(result)
+    /// Creates an interpreter for part 1, playing and recovering sounds with a [`SoundCard`].
+    pub fn for_part_1(program: Program<Instruction>) -> Self {
+        Self::with_device(program, SoundCard::default())
     }
 
-    pub fn send_count(&self) -> usize {
-        self.send_count
+    /// Creates an interpreter for part 2, to be connected in a
+    /// [`Network`](crate::helpers::duet::network::Network). Its `p` register is initialized
+    /// to its program ID.
+    pub fn networked(program: Program<Instruction>, ports: Ports) -> Self {
+        let id = ports.id() as i64;
+        let mut interpreter = Self::with_device(program, ports);
+        interpreter.set_register("p", id);
+        interpreter
     }
 }
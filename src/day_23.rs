@@ -1,43 +1,63 @@
-use std::collections::HashMap;
-use std::str::FromStr;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, ensure, Context};
+use itertools::{EitherOrBoth, Itertools};
 use primes::is_prime;
-use strum::EnumDiscriminants;
-
-use crate::helpers::duet::{read_instructions, read_register, read_value, Registers, Value};
+use strum::{Display, EnumCount, EnumDiscriminants, EnumIter, IntoEnumIterator};
+
+use crate::helpers::duet::compiled::{self, Compile, CompiledInstruction};
+use crate::helpers::duet::decompiler::{BinaryOp, Decompile, Expr, Statement};
+use crate::helpers::duet::history::Reversible;
+use crate::helpers::duet::idioms::LoopIdioms;
+use crate::helpers::duet::lint::Lint;
+use crate::helpers::duet::snapshot::SnapshotContext;
+use crate::helpers::duet::{
+    read_instructions, read_register, read_value, InstructionResult, InstructionSet, Jump,
+    JumpCondition, Operation, ParseInstruction, Program, Register, RegisterNames, Registers, Value,
+    Vm,
+};
 use crate::input::day_23::INPUT;
 
 pub fn part_1() -> usize {
     let program = Program::default();
-    let mut coprocessor = ExperimentalCoprocessor::new(program);
+    let mut coprocessor = ExperimentalCoprocessor::new(program, OpCounts::default());
+    coprocessor.compile();
 
     coprocessor.execute().unwrap();
     coprocessor.op_count(InstructionDiscriminants::Mul)
 }
 
 pub fn part_2() -> i64 {
-    let program = Program::default().optimize();
-    let mut coprocessor = ExperimentalCoprocessor::new(program);
+    let program = Program::default().optimize().unwrap();
+    let mut coprocessor = ExperimentalCoprocessor::new(program, OpCounts::default());
+    coprocessor.set_register("a", 1);
+    coprocessor.compile();
 
     coprocessor.execute().unwrap();
-    coprocessor.register('h')
+    coprocessor.register("h")
 }
 
-#[derive(Debug, Copy, Clone, EnumDiscriminants)]
-#[strum_discriminants(derive(Hash))]
-enum Instruction {
-    Set(char, Value),
-    Sub(char, Value),
-    Mul(char, Value),
+#[derive(Debug, Clone, PartialEq, Eq, EnumDiscriminants)]
+#[strum_discriminants(derive(Hash, PartialOrd, Ord, Display, EnumCount, EnumIter))]
+#[strum_discriminants(strum(serialize_all = "lowercase"))]
+pub(crate) enum Instruction {
+    Set(Register, Value),
+    Sub(Register, Value),
+    Mul(Register, Value),
     Jnz(Value, Value),
 
     // Non-standard instructions:
-    Isp(char, Value), // IS_PRIME
+    Isp(Register, Value),                  // IS_PRIME
+    Mad(Register, Value, Value),           // MULTIPLY_ADD
+    Dvt(Register, Value, Register, Value), // DIVISIBILITY_TEST
+    Jgz(Value, Value),                     // JUMP_IF_GREATER_THAN_ZERO
 }
 
-impl Instruction {
-    pub fn execute(
+impl InstructionSet for Instruction {
+    type Context = OpCounts;
+
+    fn execute(
         &self,
         registers: &mut Registers,
         op_counts: &mut OpCounts,
@@ -45,13 +65,9 @@ impl Instruction {
         op_counts.inc(self);
 
         match self {
-            Self::Set(register, value) => registers.set(*register, value.get(registers)),
-            Self::Sub(register, value) => {
-                registers.set(*register, registers.get(*register) - value.get(registers))
-            },
-            Self::Mul(register, value) => {
-                registers.set(*register, registers.get(*register) * value.get(registers))
-            },
+            Self::Set(register, value) => registers.apply(register, Operation::Set, value)?,
+            Self::Sub(register, value) => registers.apply(register, Operation::Sub, value)?,
+            Self::Mul(register, value) => registers.apply(register, Operation::Mul, value)?,
             Self::Jnz(value, jmp_offset) => {
                 if value.get(registers) != 0 {
                     return Ok(InstructionResult::JmpOffset(jmp_offset.get(registers)));
@@ -60,171 +76,521 @@ impl Instruction {
 
             // Non-standard instructions:
             Self::Isp(register, value) => {
-                registers.set(*register, if is_prime(value.get(registers) as u64) { 1 } else { 0 });
+                registers.set(register, if is_prime(value.get(registers) as u64) { 1 } else { 0 });
+            },
+            Self::Mad(register, a, b) => registers.multiply_add(register, a, b)?,
+            Self::Dvt(flag, divisor, counter, bound) => {
+                let (divisor, bound) = (divisor.get_exact(registers)?, bound.get_exact(registers)?);
+                if has_divisor_product(divisor, registers.get_exact(counter)?, bound) {
+                    registers.set(flag, 0);
+                }
+            },
+            Self::Jgz(value, jmp_offset) => {
+                if value.get(registers) > 0 {
+                    return Ok(InstructionResult::JmpOffset(jmp_offset.get(registers)));
+                }
             },
         }
 
         Ok(InstructionResult::Unit)
     }
+
+    fn jump(&self) -> Option<Jump<'_>> {
+        match self {
+            Self::Jnz(value, offset) => {
+                Some(Jump { value, condition: JumpCondition::NonZero, offset })
+            },
+            Self::Jgz(value, offset) => {
+                Some(Jump { value, condition: JumpCondition::Positive, offset })
+            },
+            _ => None,
+        }
+    }
 }
 
-impl FromStr for Instruction {
-    type Err = anyhow::Error;
+impl Compile for Instruction {
+    fn compile(&self) -> CompiledInstruction<OpCounts> {
+        let op = InstructionDiscriminants::from(self);
+        match self {
+            Self::Set(register, value) => {
+                compiled::apply(register, Operation::Set, value, counted(op))
+            },
+            Self::Sub(register, value) => {
+                compiled::apply(register, Operation::Sub, value, counted(op))
+            },
+            Self::Mul(register, value) => {
+                compiled::apply(register, Operation::Mul, value, counted(op))
+            },
+            Self::Jnz(value, offset) => {
+                compiled::jump(value, JumpCondition::NonZero, offset, counted(op))
+            },
+            Self::Isp(register, value) => {
+                compiled::update(register, value, move |_, b, op_counts: &mut OpCounts| {
+                    op_counts.inc(op);
+                    i64::from(is_prime(b as u64))
+                })
+            },
+            Self::Jgz(value, offset) => {
+                compiled::jump(value, JumpCondition::Positive, offset, counted(op))
+            },
+            Self::Mad(..) | Self::Dvt(..) => {
+                let instruction = self.clone();
+                Box::new(move |registers, op_counts| instruction.execute(registers, op_counts))
+            },
+        }
+    }
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split_whitespace();
-        let opcode = parts.next().with_context(|| "empty instruction")?;
+/// Whether `divisor * x == bound` for some `x` in `from..bound`.
+fn has_divisor_product(divisor: i64, from: i64, bound: i64) -> bool {
+    match divisor {
+        0 => bound == 0 && from < bound,
+        divisor => {
+            bound.checked_rem(divisor) == Some(0) && (from..bound).contains(&(bound / divisor))
+        },
+    }
+}
 
-        match opcode {
-            "set" => Ok(Self::Set(read_register(&mut parts)?, read_value(&mut parts)?)),
-            "sub" => Ok(Self::Sub(read_register(&mut parts)?, read_value(&mut parts)?)),
-            "mul" => Ok(Self::Mul(read_register(&mut parts)?, read_value(&mut parts)?)),
-            "jnz" => Ok(Self::Jnz(read_value(&mut parts)?, read_value(&mut parts)?)),
+impl LoopIdioms for Instruction {
+    fn replace_loop(body: &[Self]) -> Option<Vec<Self>> {
+        use Value::{Number, Register as Reg};
 
-            // Non-standard instructions:
-            "isp" => Ok(Self::Isp(read_register(&mut parts)?, read_value(&mut parts)?)),
+        match body {
+            [first, second, Self::Jnz(Reg(counter), Number(-2))] => {
+                multiply_add(first, second, counter, body)
+                    .or_else(|| multiply_add(second, first, counter, body))
+            },
 
-            opcode => Err(anyhow!("invalid opcode: {opcode}")),
+            body => divisibility_test(body),
         }
     }
 }
 
+/// Sign of the value checked by a [`guarded`] replacement.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum InstructionResult {
-    Unit,
-    JmpOffset(i64),
-    Exited,
+enum Sign {
+    Positive,
+    Negative,
 }
 
-impl InstructionResult {
-    pub fn jmp_offset(&self) -> i64 {
+/// Builds a replacement running `setup`, then `fast` if `value` has the expected `sign`,
+/// falling back to the original loop `body` otherwise.
+fn guarded(
+    setup: Vec<Instruction>,
+    value: Value,
+    sign: Sign,
+    body: &[Instruction],
+    fast: Vec<Instruction>,
+) -> Vec<Instruction> {
+    use Value::Number;
+
+    // The last instruction of the guard jumps over the fallback and the jump that ends it.
+    let to_fast = Number(body.len() as i64 + 2);
+    let mut replacement = setup;
+    match sign {
+        Sign::Positive => replacement.push(Instruction::Jgz(value, to_fast)),
+        Sign::Negative => {
+            replacement.push(Instruction::Jgz(value.clone(), Number(2)));
+            replacement.push(Instruction::Jnz(value, to_fast));
+        },
+    }
+    replacement.extend_from_slice(body);
+    replacement.push(Instruction::Jnz(Number(1), Number(fast.len() as i64 + 1)));
+    replacement.extend(fast);
+    replacement
+}
+
+/// Recognizes a loop clearing flag `f` if `d * e == b` for some `e` in `e..b`, i.e.
+/// if `b` is divisible by `d` with a quotient in that range. The loop is only replaced
+/// when `e < b`: otherwise, `e` goes past `b` and the loop only ends if it overflows.
+fn divisibility_test(body: &[Instruction]) -> Option<Vec<Instruction>> {
+    use Instruction::{Jnz, Mul, Set, Sub};
+    use Value::{Number, Register as Reg};
+
+    if body.len() != 9 {
+        return None;
+    }
+    let (
+        [Set(g, d), Mul(g1, Reg(e)), Sub(g2, b)],
+        [Jnz(Reg(g3), Number(2)), Set(f, Number(0)), Sub(e1, Number(-1))],
+        [Set(g4, Reg(e2)), Sub(g5, b1), Jnz(Reg(g6), Number(-8))],
+    ) = (&body[..3], &body[3..6], &body[6..])
+    else {
+        return None;
+    };
+
+    let is_scratch = [g1, g2, g3, g4, g5, g6].iter().all(|&r| r == g);
+    let is_counter = [e1, e2].iter().all(|&r| r == e);
+    let reads_written = [d, b]
+        .iter()
+        .any(|v| [g, e, f].iter().any(|r| v.is_register(r)));
+    if !is_scratch || !is_counter || b != b1 || reads_written || g == e || g == f || e == f {
+        return None;
+    }
+
+    // The scratch register is overwritten by the loop before being read.
+    let setup = vec![Set(g.clone(), Reg(e.clone())), Sub(g.clone(), b.clone())];
+    let fast = vec![
+        Instruction::Dvt(f.clone(), d.clone(), e.clone(), b.clone()),
+        Set(e.clone(), b.clone()),
+        Set(g.clone(), Number(0)),
+    ];
+    Some(guarded(setup, Reg(g.clone()), Sign::Negative, body, fast))
+}
+
+/// Recognizes a loop repeatedly subtracting from an accumulator with `update`, while
+/// `step` counts `counter` down to 0. The loop is only replaced when `counter` has the
+/// same sign as `step`: otherwise, it goes past 0 and the loop only ends if it overflows.
+fn multiply_add(
+    update: &Instruction,
+    step: &Instruction,
+    counter: &Register,
+    body: &[Instruction],
+) -> Option<Vec<Instruction>> {
+    let (Instruction::Sub(accumulator, value), Instruction::Sub(stepped, Value::Number(step))) =
+        (update, step)
+    else {
+        return None;
+    };
+    if stepped != counter
+        || accumulator == counter
+        || value.is_register(accumulator)
+        || value.is_register(counter)
+    {
+        return None;
+    }
+
+    let total = Value::Register(counter.clone());
+    let mut fast = match (step, value) {
+        // Runs `counter` times: accumulator -= value * counter.
+        (1, Value::Number(n)) => {
+            vec![Instruction::Mad(accumulator.clone(), Value::Number(n.checked_neg()?), total)]
+        },
+        (1, value) => vec![
+            Instruction::Mul(counter.clone(), Value::Number(-1)),
+            Instruction::Mad(accumulator.clone(), value.clone(), total),
+        ],
+        // Runs `-counter` times: accumulator += value * counter.
+        (-1, value) => vec![Instruction::Mad(accumulator.clone(), value.clone(), total)],
+        _ => return None,
+    };
+    fast.push(Instruction::Set(counter.clone(), Value::Number(0)));
+
+    let sign = if *step > 0 { Sign::Positive } else { Sign::Negative };
+    Some(guarded(Vec::new(), Value::Register(counter.clone()), sign, body, fast))
+}
+
+/// Only op counts need to be restored.
+impl Reversible for Instruction {
+    fn undo(&self, op_counts: &mut OpCounts, _: &InstructionResult) -> Result<(), anyhow::Error> {
+        op_counts.dec(self);
+        Ok(())
+    }
+}
+
+impl Lint for Instruction {
+    fn reads(&self) -> Vec<&Register> {
+        let (register, values) = match self {
+            Self::Set(_, value) | Self::Isp(_, value) => (None, vec![value]),
+            Self::Sub(register, value) | Self::Mul(register, value) => {
+                (Some(register), vec![value])
+            },
+            Self::Jnz(value, offset) | Self::Jgz(value, offset) => (None, vec![value, offset]),
+            Self::Mad(register, a, b) => (Some(register), vec![a, b]),
+            Self::Dvt(_, divisor, counter, bound) => (Some(counter), vec![divisor, bound]),
+        };
+        register
+            .into_iter()
+            .chain(values.into_iter().filter_map(Value::register))
+            .collect()
+    }
+
+    fn writes(&self) -> Option<&Register> {
         match self {
-            Self::JmpOffset(offset) => *offset,
-            _ => 1,
+            Self::Set(register, _)
+            | Self::Sub(register, _)
+            | Self::Mul(register, _)
+            | Self::Isp(register, _)
+            | Self::Mad(register, ..)
+            | Self::Dvt(register, ..) => Some(register),
+            Self::Jnz(..) | Self::Jgz(..) => None,
         }
     }
 }
 
-#[derive(Debug)]
-struct Program {
-    instructions: Vec<Instruction>,
+impl Decompile for Instruction {
+    fn statement(&self) -> Option<Statement> {
+        Some(match self {
+            Self::Set(register, value) => Statement::apply(register, Operation::Set, value),
+            Self::Sub(register, value) => Statement::apply(register, Operation::Sub, value),
+            Self::Mul(register, value) => Statement::apply(register, Operation::Mul, value),
+            Self::Jnz(..) | Self::Jgz(..) => return None,
+
+            // Non-standard instructions:
+            Self::Isp(register, value) => {
+                Statement::Assign(register.clone(), Expr::call("is_prime", vec![value.into()]))
+            },
+            Self::Mad(register, a, b) => Statement::Assign(
+                register.clone(),
+                Expr::binary(
+                    Expr::Register(register.clone()),
+                    BinaryOp::Add,
+                    Expr::binary(a.into(), BinaryOp::Mul, b.into()),
+                ),
+            ),
+            Self::Dvt(flag, divisor, counter, bound) => Statement::If(
+                Expr::call(
+                    "has_divisor_product",
+                    vec![divisor.into(), Expr::Register(counter.clone()), bound.into()],
+                ),
+                vec![Statement::Assign(flag.clone(), Expr::Number(0))],
+                Vec::new(),
+            ),
+        })
+    }
 }
 
-impl Program {
-    pub fn execute(
-        &self,
-        ip: i64,
-        registers: &mut Registers,
-        op_counts: &mut OpCounts,
-    ) -> Result<InstructionResult, anyhow::Error> {
-        usize::try_from(ip)
-            .ok()
-            .and_then(|ip| self.instructions.get(ip))
-            .map(|instruction| instruction.execute(registers, op_counts))
-            .unwrap_or(Ok(InstructionResult::Exited))
+/// Returns a hook counting executions of `op` in compiled instructions.
+fn counted(op: InstructionDiscriminants) -> impl Fn(&mut OpCounts) {
+    move |op_counts| op_counts.inc(op)
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Set(register, value) => write!(f, "set {register} {value}"),
+            Self::Sub(register, value) => write!(f, "sub {register} {value}"),
+            Self::Mul(register, value) => write!(f, "mul {register} {value}"),
+            Self::Jnz(value, jmp_offset) => write!(f, "jnz {value} {jmp_offset}"),
+
+            // Non-standard instructions:
+            Self::Isp(register, value) => write!(f, "isp {register} {value}"),
+            Self::Mad(register, a, b) => write!(f, "mad {register} {a} {b}"),
+            Self::Dvt(flag, divisor, counter, bound) => {
+                write!(f, "dvt {flag} {divisor} {counter} {bound}")
+            },
+            Self::Jgz(value, jmp_offset) => write!(f, "jgz {value} {jmp_offset}"),
+        }
     }
 }
 
-impl FromStr for Program {
-    type Err = anyhow::Error;
+impl ParseInstruction for Instruction {
+    fn parse(s: &str, names: &mut RegisterNames) -> Result<Self, anyhow::Error> {
+        let mut parts = s.split_whitespace();
+        let opcode = parts.next().with_context(|| "empty instruction")?;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self { instructions: read_instructions(s)? })
+        match opcode {
+            "set" => {
+                Ok(Self::Set(read_register(&mut parts, names)?, read_value(&mut parts, names)?))
+            },
+            "sub" => {
+                Ok(Self::Sub(read_register(&mut parts, names)?, read_value(&mut parts, names)?))
+            },
+            "mul" => {
+                Ok(Self::Mul(read_register(&mut parts, names)?, read_value(&mut parts, names)?))
+            },
+            "jnz" => Ok(Self::Jnz(read_value(&mut parts, names)?, read_value(&mut parts, names)?)),
+
+            // Non-standard instructions:
+            "isp" => {
+                Ok(Self::Isp(read_register(&mut parts, names)?, read_value(&mut parts, names)?))
+            },
+            "mad" => Ok(Self::Mad(
+                read_register(&mut parts, names)?,
+                read_value(&mut parts, names)?,
+                read_value(&mut parts, names)?,
+            )),
+            "dvt" => Ok(Self::Dvt(
+                read_register(&mut parts, names)?,
+                read_value(&mut parts, names)?,
+                read_register(&mut parts, names)?,
+                read_value(&mut parts, names)?,
+            )),
+            "jgz" => Ok(Self::Jgz(read_value(&mut parts, names)?, read_value(&mut parts, names)?)),
+
+            opcode => Err(anyhow!("invalid opcode: {opcode}")),
+        }
     }
 }
 
-impl Default for Program {
+impl Default for Program<Instruction> {
     fn default() -> Self {
         INPUT.parse().unwrap()
     }
 }
 
 #[derive(Debug, Default)]
-struct OpCounts(HashMap<InstructionDiscriminants, usize>);
+pub(crate) struct OpCounts([usize; InstructionDiscriminants::COUNT]);
 
 impl OpCounts {
     pub fn inc<I>(&mut self, instruction: I)
     where
         I: Into<InstructionDiscriminants>,
     {
-        *self.0.entry(instruction.into()).or_default() += 1;
+        self.0[instruction.into() as usize] += 1;
+    }
+
+    pub fn dec<I>(&mut self, instruction: I)
+    where
+        I: Into<InstructionDiscriminants>,
+    {
+        self.0[instruction.into() as usize] -= 1;
     }
 
     pub fn count<I>(&self, instruction: I) -> usize
     where
         I: Into<InstructionDiscriminants>,
     {
-        self.0.get(&instruction.into()).copied().unwrap_or_default()
+        self.0[instruction.into() as usize]
     }
 }
 
-#[derive(Debug)]
-struct ExperimentalCoprocessor {
-    program: Program,
-    registers: Registers,
-    ip: i64,
-    op_counts: OpCounts,
+impl fmt::Display for OpCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "op counts: {}",
+            InstructionDiscriminants::iter()
+                .zip(self.0)
+                .filter(|&(_, count)| count > 0)
+                .map(|(op, count)| format!("{op}: {count}"))
+                .join(", ")
+        )
+    }
 }
 
-impl ExperimentalCoprocessor {
-    pub fn new(program: Program) -> Self {
-        Self { program, registers: Registers::default(), ip: 0, op_counts: OpCounts::default() }
-    }
+/// Op counts are saved by opcode name.
+impl SnapshotContext for OpCounts {
+    type Snapshot = BTreeMap<String, usize>;
 
-    pub fn execute_next(&mut self) -> Result<InstructionResult, anyhow::Error> {
-        let result = self
-            .program
-            .execute(self.ip, &mut self.registers, &mut self.op_counts)?;
-        self.ip += result.jmp_offset();
-        Ok(result)
+    fn snapshot(&self) -> BTreeMap<String, usize> {
+        InstructionDiscriminants::iter()
+            .map(|op| (op.to_string(), self.count(op)))
+            .collect()
     }
 
-    pub fn execute(&mut self) -> Result<(), anyhow::Error> {
-        while self.execute_next()? != InstructionResult::Exited {}
-
+    fn restore(&mut self, snapshot: BTreeMap<String, usize>) -> Result<(), anyhow::Error> {
+        let mut counts = Self::default();
+        for (name, count) in snapshot {
+            let op = InstructionDiscriminants::iter()
+                .find(|op| op.to_string() == name)
+                .with_context(|| format!("invalid opcode: {name}"))?;
+            counts.0[op as usize] = count;
+        }
+        *self = counts;
         Ok(())
     }
+}
+
+pub(crate) type ExperimentalCoprocessor = Vm<Instruction>;
 
+impl ExperimentalCoprocessor {
     pub fn op_count<I>(&self, instruction: I) -> usize
     where
         I: Into<InstructionDiscriminants>,
     {
-        self.op_counts.count(instruction)
-    }
-
-    pub fn register(&self, register: char) -> i64 {
-        self.registers.get(register)
+        self.context().count(instruction)
     }
 }
 
-/// The initial program counts the number of non-prime numbers
-/// between two numbers (inclusive), stepping by 17.
+/// Shape of the program: it counts the number of non-prime numbers
+/// between `b` and `c` (inclusive), stepping by `-?step`.
 ///
-/// This version is "optimized", but it uses a non-standard
+/// The prologue computes `b` and `c`, with different constants when `a` is set;
+/// numbers written `?name` vary from one input to another.
+const PATTERN: &str = "set b ?b\n\
+                       set c b\n\
+                       jnz a 2\n\
+                       jnz 1 5\n\
+                       mul b ?factor\n\
+                       sub b ?b_offset\n\
+                       set c b\n\
+                       sub c ?c_offset\n\
+                       set f 1\n\
+                       set d 2\n\
+                       set e 2\n\
+                       set g d\n\
+                       mul g e\n\
+                       sub g b\n\
+                       jnz g 2\n\
+                       set f 0\n\
+                       sub e -1\n\
+                       set g e\n\
+                       sub g b\n\
+                       jnz g -8\n\
+                       sub d -1\n\
+                       set g d\n\
+                       sub g b\n\
+                       jnz g -13\n\
+                       jnz f 2\n\
+                       sub h -1\n\
+                       set g b\n\
+                       sub g c\n\
+                       jnz g 2\n\
+                       jnz 1 3\n\
+                       sub b ?step\n\
+                       jnz 1 -23";
+
+/// Number of instructions of the prologue in [`PATTERN`], kept as is when optimizing.
+const PROLOGUE_LEN: usize = 8;
+
+/// Counting loop of [`PATTERN`], "optimized" using a non-standard
 /// instruction (`isp`) to determine if a number is prime.
-///
-/// To use, keep the program's first instruction and replace
-/// the rest with this.
-const OPTIMIZED: &str = "mul b 100\n\
-                         sub b -100000\n\
-                         set c b\n\
-                         sub c -17000\n\
+const OPTIMIZED: &str = "next:\n\
                          isp f b\n\
-                         jnz f 2\n\
+                         jnz f composite\n\
                          sub h -1\n\
+                         composite:\n\
                          set g b\n\
                          sub g c\n\
-                         jnz g 2\n\
-                         jnz 1 42\n\
-                         sub b -17\n\
-                         jnz g -8";
-
-impl Program {
-    pub fn optimize(mut self) -> Self {
-        let mut optimized: Program = OPTIMIZED.parse().unwrap();
-        optimized
-            .instructions
-            .insert(0, self.instructions.remove(0));
-        optimized
+                         jnz g continue\n\
+                         jnz 1 end\n\
+                         continue:\n\
+                         sub b ?step\n\
+                         jnz 1 next\n\
+                         end:";
+
+impl Program<Instruction> {
+    /// Replaces the program's counting loop with a faster one, keeping its constants.
+    ///
+    /// Fails if the program does not have the expected shape.
+    pub fn optimize(self) -> Result<Self, anyhow::Error> {
+        let constants = self.match_pattern().context("unrecognized program")?;
+        let optimized = OPTIMIZED.replace("?step", &constants["step"].to_string());
+
+        let (mut instructions, mut names) = self.into_parts();
+        instructions.truncate(PROLOGUE_LEN);
+        instructions.extend(read_instructions(&optimized, &mut names)?);
+        Ok(Self::new(instructions, names))
+    }
+
+    /// Matches the program against [`PATTERN`], returning the value of its constants.
+    fn match_pattern(&self) -> Result<HashMap<&'static str, i64>, anyhow::Error> {
+        let pattern = PATTERN.lines().collect_vec();
+        ensure!(
+            self.len() == pattern.len(),
+            "expected {} instructions, found {}",
+            pattern.len(),
+            self.len(),
+        );
+
+        let mut constants = HashMap::new();
+        for (ip, (instruction, expected)) in self.instructions().iter().zip(pattern).enumerate() {
+            let actual = instruction.to_string();
+            let tokens = actual
+                .split_whitespace()
+                .zip_longest(expected.split_whitespace());
+            let matches = tokens.into_iter().all(|tokens| match tokens {
+                EitherOrBoth::Both(token, expected) => match expected.strip_prefix('?') {
+                    Some(name) => token
+                        .parse()
+                        .is_ok_and(|value| *constants.entry(name).or_insert(value) == value),
+                    None => token == expected,
+                },
+                _ => false,
+            });
+            ensure!(matches, "instruction {ip} is `{actual}`, expected `{expected}`");
+        }
+        Ok(constants)
     }
 }
@@ -1,86 +1,1606 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
 
-use anyhow::Context;
+use anyhow::{ensure, Context};
+use itertools::Itertools;
+use num::{BigInt, Signed, ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default)]
-pub struct Queue(VecDeque<i64>);
+use crate::helpers::duet::compiled::{Compile, CompiledProgram};
+use crate::helpers::duet::history::{Delta, History};
+use crate::helpers::duet::profiler::Profile;
+use crate::helpers::duet::trace::{RegisterValues, RegisterWrite, TraceSink, TraceStep};
+
+pub mod cfg;
+pub mod compiled;
+pub mod debugger;
+pub mod decompiler;
+pub mod device;
+pub mod history;
+pub mod idioms;
+pub mod lint;
+pub mod network;
+pub mod profiler;
+pub mod snapshot;
+pub mod trace;
+
+/// Queue of values exchanged by duet programs, optionally bounded.
+#[derive(Debug, Default, Hash)]
+pub struct Queue {
+    values: VecDeque<i64>,
+    capacity: Option<usize>,
+}
 
 impl Queue {
+    /// Creates a queue that can hold at most `capacity` values; see [`Queue::is_full`].
+    pub fn bounded(capacity: usize) -> Self {
+        assert!(capacity > 0, "queue capacity must be positive");
+        Self { values: VecDeque::with_capacity(capacity), capacity: Some(capacity) }
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Whether the queue is bounded and has reached its capacity.
+    ///
+    /// Pushing to a full queue is still possible; it is up to senders to wait.
+    pub fn is_full(&self) -> bool {
+        self.capacity
+            .is_some_and(|capacity| self.values.len() >= capacity)
+    }
+
     pub fn push(&mut self, value: i64) {
-        self.0.push_back(value)
+        self.values.push_back(value)
     }
 
     pub fn pop(&mut self) -> Option<i64> {
-        self.0.pop_front()
+        self.values.pop_front()
+    }
+
+    /// Puts back a value that was popped, so that it is popped again first.
+    pub fn unpop(&mut self, value: i64) {
+        self.values.push_front(value)
+    }
+
+    pub fn last(&self) -> Option<i64> {
+        self.values.back().copied()
     }
 
     pub fn pop_last(&mut self) -> Option<i64> {
-        self.0.pop_back()
+        self.values.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &i64> {
+        self.values.iter()
+    }
+}
+
+impl fmt::Display for Queue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}]", self.values.iter().join(", "))
+    }
+}
+
+/// A register referenced by a program.
+///
+/// Registers are interned when a program is parsed (see [`RegisterNames`]), so each
+/// register name is mapped to a dense index that can be used to look up the register's
+/// value in a [`Registers`] file without hashing.
+///
+/// Registers are compared by name only, since their index depends on the order in which
+/// they first appear in a program.
+#[derive(Debug, Clone)]
+pub struct Register {
+    index: usize,
+    name: Arc<str>,
+}
+
+impl Register {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl PartialEq for Register {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Register {}
+
+impl Hash for Register {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Table of register names used by a program, mapping each name to a dense index.
+#[derive(Debug, Default, Clone)]
+pub struct RegisterNames {
+    registers: Vec<Register>,
+    indices: HashMap<Arc<str>, usize>,
+}
+
+impl RegisterNames {
+    /// Returns the [`Register`] with the given name, adding it to the table if needed.
+    ///
+    /// Register names must be identifiers: an ASCII letter or underscore, followed by
+    /// any number of ASCII letters, digits or underscores.
+    pub fn intern(&mut self, name: &str) -> Result<Register, anyhow::Error> {
+        if let Some(register) = self.get(name) {
+            return Ok(register);
+        }
+
+        ensure!(Self::is_valid_name(name), "invalid register name: {name}");
+
+        let register = Register { index: self.registers.len(), name: name.into() };
+        self.indices
+            .insert(Arc::clone(&register.name), register.index);
+        self.registers.push(register.clone());
+        Ok(register)
+    }
+
+    /// Returns the [`Register`] with the given name, if the program uses it.
+    pub fn get(&self, name: &str) -> Option<Register> {
+        self.indices
+            .get(name)
+            .map(|&index| self.registers[index].clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.registers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Register> {
+        self.registers.iter()
+    }
+
+    fn is_valid_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+}
+
+/// How arithmetic instructions handle results that don't fit in an `i64`;
+/// see [`Vm::set_arithmetic`].
+///
+/// In every mode, `mod` by zero is an error.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum Arithmetic {
+    /// Overflows are errors.
+    #[default]
+    Checked,
+
+    /// Results wrap around, like [`i64::wrapping_add`] and friends.
+    Wrapping,
+
+    /// Results are exact: registers whose values don't fit in an `i64` hold a [`BigInt`].
+    BigInt,
+}
+
+impl Arithmetic {
+    /// Computes `a <operation> b`, returning `None` if the result needs a [`BigInt`].
+    fn apply(self, operation: Operation, a: i64, b: i64) -> Result<Option<i64>, anyhow::Error> {
+        ensure!(operation != Operation::Mod || b != 0, "modulo by zero");
+        if self == Self::Wrapping {
+            return Ok(Some(match operation {
+                Operation::Set => b,
+                Operation::Add => a.wrapping_add(b),
+                Operation::Sub => a.wrapping_sub(b),
+                Operation::Mul => a.wrapping_mul(b),
+                Operation::Mod => a.wrapping_rem_euclid(b),
+            }));
+        }
+
+        let result = match operation {
+            Operation::Set => Some(b),
+            Operation::Add => a.checked_add(b),
+            Operation::Sub => a.checked_sub(b),
+            Operation::Mul => a.checked_mul(b),
+            Operation::Mod => a.checked_rem_euclid(b),
+        };
+        ensure!(
+            result.is_some() || self == Self::BigInt,
+            "arithmetic overflow in {a} {operation} {b}"
+        );
+        Ok(result)
+    }
+}
+
+/// Operation performed by arithmetic instructions; see [`Registers::apply`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Copies the value.
+    Set,
+    Add,
+    Sub,
+    Mul,
+
+    /// Euclidean remainder, which is never negative.
+    Mod,
+}
+
+impl Operation {
+    fn apply_big(self, a: BigInt, b: BigInt) -> Result<BigInt, anyhow::Error> {
+        Ok(match self {
+            Self::Set => b,
+            Self::Add => a + b,
+            Self::Sub => a - b,
+            Self::Mul => a * b,
+            Self::Mod => {
+                ensure!(!b.is_zero(), "modulo by zero");
+                let remainder = a % &b;
+                if remainder.is_negative() {
+                    remainder + b.abs()
+                } else {
+                    remainder
+                }
+            },
+        })
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Set => write!(f, "="),
+            Self::Add => write!(f, "+"),
+            Self::Sub => write!(f, "-"),
+            Self::Mul => write!(f, "*"),
+            Self::Mod => write!(f, "mod"),
+        }
     }
 }
 
-#[derive(Debug, Default)]
-pub struct Registers(HashMap<char, i64>);
+/// Values of the registers used by a program, indexed by [`Register::index`],
+/// along with the [`Arithmetic`] used to update them.
+///
+/// Values that don't fit in an `i64` (only with [`Arithmetic::BigInt`]) are kept separately;
+/// [`Registers::get`] returns them saturated to `i64::MIN` or `i64::MAX`, which keeps their sign.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Registers {
+    values: Vec<i64>,
+    big: BTreeMap<usize, BigInt>,
+    arithmetic: Arithmetic,
+}
 
 impl Registers {
-    pub fn get(&self, register: char) -> i64 {
-        self.0.get(&register).copied().unwrap_or_default()
+    /// Returns a register file for all registers in `names`, initialized to `0`.
+    pub fn new(names: &RegisterNames) -> Self {
+        Self {
+            values: vec![0; names.len()],
+            big: BTreeMap::new(),
+            arithmetic: Arithmetic::default(),
+        }
+    }
+
+    pub fn arithmetic(&self) -> Arithmetic {
+        self.arithmetic
+    }
+
+    /// Changes the arithmetic used by further operations. When leaving [`Arithmetic::BigInt`],
+    /// registers that don't fit in an `i64` keep their saturated value.
+    pub fn set_arithmetic(&mut self, arithmetic: Arithmetic) {
+        self.arithmetic = arithmetic;
+        if arithmetic != Arithmetic::BigInt {
+            self.big.clear();
+        }
+    }
+
+    /// Returns the value of `register`, saturated if it doesn't fit in an `i64`.
+    pub fn get(&self, register: &Register) -> i64 {
+        self.values[register.index]
+    }
+
+    /// Returns the value of `register`, failing if it doesn't fit in an `i64`.
+    pub fn get_exact(&self, register: &Register) -> Result<i64, anyhow::Error> {
+        ensure!(!self.is_big(register), "value of register {register} is too large");
+        Ok(self.get(register))
+    }
+
+    /// Returns the exact value of `register`.
+    pub fn get_big(&self, register: &Register) -> BigInt {
+        match self.big.get(&register.index) {
+            Some(value) => value.clone(),
+            None => self.get(register).into(),
+        }
+    }
+
+    pub fn set(&mut self, register: &Register, value: i64) {
+        self.values[register.index] = value;
+        if !self.big.is_empty() {
+            self.big.remove(&register.index);
+        }
+    }
+
+    /// Sets `register` to `value`, which must fit in an `i64` unless using [`Arithmetic::BigInt`].
+    pub fn set_big(&mut self, register: &Register, value: BigInt) -> Result<(), anyhow::Error> {
+        if let Some(value) = value.to_i64() {
+            self.set(register, value);
+            return Ok(());
+        }
+        ensure!(
+            self.arithmetic == Arithmetic::BigInt,
+            "value of register {register} is too large for {:?} arithmetic",
+            self.arithmetic
+        );
+        self.values[register.index] = if value.is_negative() { i64::MIN } else { i64::MAX };
+        self.big.insert(register.index, value);
+        Ok(())
+    }
+
+    /// Whether `register` holds a value that doesn't fit in an `i64`.
+    pub fn is_big(&self, register: &Register) -> bool {
+        !self.big.is_empty() && self.big.contains_key(&register.index)
+    }
+
+    /// Sets `register` to `register <operation> value`, following the registers' [`Arithmetic`].
+    pub fn apply(
+        &mut self,
+        register: &Register,
+        operation: Operation,
+        value: &Value,
+    ) -> Result<(), anyhow::Error> {
+        if !self.is_big(register) && !value.is_big(self) {
+            let (a, b) = (self.get(register), value.get(self));
+            if let Some(result) = self.arithmetic.apply(operation, a, b)? {
+                self.set(register, result);
+                return Ok(());
+            }
+        }
+        let result = operation.apply_big(self.get_big(register), value.get_big(self))?;
+        self.set_big(register, result)
+    }
+
+    /// Sets `register` to `register + a * b`, following the registers' [`Arithmetic`].
+    pub fn multiply_add(
+        &mut self,
+        register: &Register,
+        a: &Value,
+        b: &Value,
+    ) -> Result<(), anyhow::Error> {
+        if !self.is_big(register) && !a.is_big(self) && !b.is_big(self) {
+            let product = self
+                .arithmetic
+                .apply(Operation::Mul, a.get(self), b.get(self))?;
+            let result = match product {
+                Some(product) => {
+                    self.arithmetic
+                        .apply(Operation::Add, self.get(register), product)?
+                },
+                None => None,
+            };
+            if let Some(result) = result {
+                self.set(register, result);
+                return Ok(());
+            }
+        }
+        let result = self.get_big(register) + a.get_big(self) * b.get_big(self);
+        self.set_big(register, result)
     }
 
-    pub fn set(&mut self, register: char, value: i64) {
-        self.0.insert(register, value);
+    /// Returns the index, old value and new value of every register whose value
+    /// differs from `before`. Values that don't fit in an `i64` are saturated.
+    pub fn changes<'a>(
+        &'a self,
+        before: &'a Registers,
+    ) -> impl Iterator<Item = (usize, i64, i64)> + 'a {
+        before
+            .values
+            .iter()
+            .zip(&self.values)
+            .enumerate()
+            .filter(|&(index, (old, new))| {
+                old != new || before.big.get(&index) != self.big.get(&index)
+            })
+            .map(|(index, (&old, &new))| (index, old, new))
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Value {
     Number(i64),
-    Register(char),
+    Register(Register),
 }
 
 impl Value {
     pub fn get(&self, registers: &Registers) -> i64 {
         match self {
             Self::Number(n) => *n,
-            Self::Register(register) => registers.get(*register),
+            Self::Register(register) => registers.get(register),
         }
     }
-}
 
-impl FromStr for Value {
-    type Err = anyhow::Error;
+    /// Returns the value, failing if it doesn't fit in an `i64`; see [`Registers::get_exact`].
+    pub fn get_exact(&self, registers: &Registers) -> Result<i64, anyhow::Error> {
+        match self {
+            Self::Number(n) => Ok(*n),
+            Self::Register(register) => registers.get_exact(register),
+        }
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+    pub fn get_big(&self, registers: &Registers) -> BigInt {
+        match self {
+            Self::Number(n) => (*n).into(),
+            Self::Register(register) => registers.get_big(register),
+        }
+    }
+
+    /// Whether the value is read from a register that doesn't fit in an `i64`.
+    pub fn is_big(&self, registers: &Registers) -> bool {
+        matches!(self, Self::Register(register) if registers.is_big(register))
+    }
+
+    /// Returns the register the value is read from, if any.
+    pub fn register(&self) -> Option<&Register> {
+        match self {
+            Self::Number(_) => None,
+            Self::Register(register) => Some(register),
+        }
+    }
+
+    /// Whether the value is read from `register`.
+    pub fn is_register(&self, register: &Register) -> bool {
+        matches!(self, Self::Register(r) if r == register)
+    }
+
+    /// Parses a [`Value`], which is either a number or a register name.
+    pub fn parse(s: &str, names: &mut RegisterNames) -> Result<Self, anyhow::Error> {
         Ok(match s.parse::<i64>() {
             Ok(n) => Self::Number(n),
-            Err(_) => Self::Register(s.chars().next().with_context(|| "empty value")?),
+            Err(_) => Self::Register(names.intern(s)?),
         })
     }
 }
 
-pub fn read_register<'a, I>(parts: &mut I) -> Result<char, anyhow::Error>
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{n}"),
+            Self::Register(register) => write!(f, "{register}"),
+        }
+    }
+}
+
+/// Instruction that can be parsed from a line of a duet program.
+pub trait ParseInstruction: Sized {
+    /// Parses an instruction, interning the registers it uses in `names`.
+    fn parse(s: &str, names: &mut RegisterNames) -> Result<Self, anyhow::Error>;
+}
+
+/// Instruction of a duet-like instruction set, executable by a [`Vm`].
+///
+/// Instructions must be displayed in a format that [`ParseInstruction::parse`] accepts.
+pub trait InstructionSet: ParseInstruction + fmt::Display {
+    /// Machine state other than registers and instruction pointer (queues, counters, etc.)
+    type Context;
+
+    /// Executes the instruction, returning how the [`Vm`] should proceed.
+    fn execute(
+        &self,
+        registers: &mut Registers,
+        context: &mut Self::Context,
+    ) -> Result<InstructionResult, anyhow::Error>;
+
+    /// Returns a description of the jump performed by this instruction, if it is a jump.
+    fn jump(&self) -> Option<Jump<'_>> {
+        None
+    }
+
+    /// Feeds the parts of `context` that influence execution (e.g. queue contents) to `state`;
+    /// used by [`Vm::detect_infinite_loops`]. Counters that merely observe execution must be
+    /// left out, otherwise no state would ever repeat.
+    ///
+    /// The default implementation feeds nothing, which is correct for contexts that do not
+    /// influence execution.
+    fn hash_context<H>(_context: &Self::Context, _state: &mut H)
+    where
+        H: Hasher,
+    {
+    }
+}
+
+/// Condition under which a [`Jump`] is taken.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum JumpCondition {
+    /// Jump if the value is not zero (`jnz`).
+    NonZero,
+
+    /// Jump if the value is greater than zero (`jgz`).
+    Positive,
+}
+
+impl JumpCondition {
+    pub fn is_met(&self, value: i64) -> bool {
+        match self {
+            Self::NonZero => value != 0,
+            Self::Positive => value > 0,
+        }
+    }
+}
+
+/// Relative jump performed by an instruction when `value` meets `condition`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Jump<'a> {
+    pub value: &'a Value,
+    pub condition: JumpCondition,
+    pub offset: &'a Value,
+}
+
+impl<'a> Jump<'a> {
+    /// Returns whether the jump is always taken, never taken, or `None` if it depends
+    /// on the value of a register.
+    pub fn is_taken(&self) -> Option<bool> {
+        match self.value {
+            Value::Number(n) => Some(self.condition.is_met(*n)),
+            Value::Register(_) => None,
+        }
+    }
+
+    /// Returns the target of the jump when performed by the instruction at `ip`,
+    /// or `None` if the offset is stored in a register.
+    pub fn target(&self, ip: i64) -> Option<i64> {
+        match self.offset {
+            Value::Number(offset) => Some(ip.saturating_add(*offset)),
+            Value::Register(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstructionResult {
+    Unit,
+    JmpOffset(i64),
+    Sent(i64),
+    Received(i64),
+
+    /// Instruction could not complete (e.g. `rcv` on an empty queue); it will be
+    /// executed again on the next step.
+    Waiting,
+
+    /// Instruction pointer is outside the program. Once a [`Vm`] has exited,
+    /// all further steps also return `Exited`.
+    Exited,
+}
+
+impl InstructionResult {
+    pub fn jmp_offset(&self) -> i64 {
+        match self {
+            Self::JmpOffset(offset) => *offset,
+            Self::Waiting | Self::Exited => 0,
+            _ => 1,
+        }
+    }
+
+    pub fn sent(&self) -> Option<i64> {
+        match self {
+            Self::Sent(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn received(&self) -> Option<i64> {
+        match self {
+            Self::Received(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Whether the [`Vm`] cannot make progress by itself after this result.
+    pub fn is_stalled(&self) -> bool {
+        matches!(self, Self::Waiting | Self::Exited)
+    }
+}
+
+/// Error returned by a [`Vm`] that was stopped before its program could exit.
+///
+/// This is wrapped in an [`anyhow::Error`]; use [`downcast_ref`](anyhow::Error::downcast_ref)
+/// to inspect it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// The machine reached its step limit; see [`Vm::set_step_limit`].
+    StepLimitExceeded { limit: u64 },
+
+    /// The machine reached a state it was already in, so it will never exit;
+    /// see [`Vm::detect_infinite_loops`].
+    InfiniteLoop {
+        /// Step at which the machine was first in the repeated state.
+        start: u64,
+
+        /// Number of steps in the cycle.
+        length: u64,
+    },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StepLimitExceeded { limit } => write!(f, "step limit of {limit} exceeded"),
+            Self::InfiniteLoop { start, length } => {
+                write!(f, "infinite loop of {length} steps starting at step {start}")
+            },
+        }
+    }
+}
+
+impl Error for VmError {}
+
+/// Parsed duet program, along with the names of the registers it uses.
+///
+/// Programs are compared by instructions only.
+#[derive(Debug, Clone)]
+pub struct Program<I> {
+    instructions: Vec<I>,
+    names: RegisterNames,
+}
+
+impl<I> Program<I> {
+    pub fn new(instructions: Vec<I>, names: RegisterNames) -> Self {
+        Self { instructions, names }
+    }
+
+    pub fn instructions(&self) -> &[I] {
+        &self.instructions
+    }
+
+    pub fn names(&self) -> &RegisterNames {
+        &self.names
+    }
+
+    /// Returns the instruction at `ip`, or `None` if `ip` is outside the program.
+    pub fn get(&self, ip: i64) -> Option<&I> {
+        usize::try_from(ip)
+            .ok()
+            .and_then(|ip| self.instructions.get(ip))
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    pub fn into_parts(self) -> (Vec<I>, RegisterNames) {
+        (self.instructions, self.names)
+    }
+}
+
+impl<I> Program<I>
+where
+    I: InstructionSet,
+{
+    /// Returns an annotated listing of the program, showing the index of each instruction
+    /// and the resolved targets of jumps.
+    pub fn listing(&self) -> Listing<'_, I> {
+        Listing(self)
+    }
+
+    /// Returns the program's source, with labels for the targets of jumps.
+    /// The result can be parsed back into the same program.
+    pub fn disassemble(&self) -> Disassembly<'_, I> {
+        Disassembly(self)
+    }
+
+    /// Returns the label name of every instruction pointer targeted by a jump with a constant
+    /// offset, including the end of the program. Label names never clash with register names.
+    fn labels(&self) -> BTreeMap<i64, String> {
+        let end = self.len() as i64;
+        self.instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(ip, instruction)| {
+                let jump = instruction.jump()?;
+                if jump.is_taken() == Some(false) {
+                    return None;
+                }
+                jump.target(ip as i64)
+                    .filter(|target| (0..=end).contains(target))
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|target| {
+                let mut label = format!("l{target}");
+                while self.names.get(&label).is_some() {
+                    label.push('_');
+                }
+                (target, label)
+            })
+            .collect()
+    }
+}
+
+impl<I> PartialEq for Program<I>
+where
+    I: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.instructions == other.instructions
+    }
+}
+
+impl<I> Eq for Program<I> where I: Eq {}
+
+impl<I> fmt::Display for Program<I>
+where
+    I: fmt::Display,
+{
+    /// Displays the program in a format that can be parsed back.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.instructions.iter().join("\n"))
+    }
+}
+
+/// Annotated listing of a [`Program`]. See [`Program::listing`].
+#[derive(Debug)]
+pub struct Listing<'a, I>(&'a Program<I>);
+
+impl<'a, I> fmt::Display for Listing<'a, I>
+where
+    I: InstructionSet,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let program = self.0;
+        let ip_width = program.len().saturating_sub(1).to_string().len();
+        let instruction_width = program
+            .instructions
+            .iter()
+            .map(|instruction| instruction.to_string().len())
+            .max()
+            .unwrap_or_default();
+
+        for (ip, instruction) in program.instructions.iter().enumerate() {
+            let instruction = instruction.to_string();
+            match jump_annotation(program, ip) {
+                Some(annotation) => writeln!(
+                    f,
+                    "{ip:>ip_width$}: {instruction:<instruction_width$}  ; {annotation}"
+                )?,
+                None => writeln!(f, "{ip:>ip_width$}: {instruction}")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Source of a [`Program`] with labelled jump targets. See [`Program::disassemble`].
+#[derive(Debug)]
+pub struct Disassembly<'a, I>(&'a Program<I>);
+
+impl<'a, I> fmt::Display for Disassembly<'a, I>
+where
+    I: InstructionSet,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let program = self.0;
+        let labels = program.labels();
+
+        for (ip, instruction) in program.instructions.iter().enumerate() {
+            if let Some(label) = labels.get(&(ip as i64)) {
+                writeln!(f, "{label}:")?;
+            }
+
+            let text = instruction.to_string();
+            let target = instruction
+                .jump()
+                .filter(|jump| jump.is_taken() != Some(false))
+                .and_then(|jump| Some((jump.offset, jump.target(ip as i64)?)));
+            match target.and_then(|(offset, target)| Some((offset, labels.get(&target)?))) {
+                // Jump offsets are the last operand of jump instructions.
+                Some((offset, label)) => {
+                    let operands = text
+                        .strip_suffix(&offset.to_string())
+                        .expect("jump offset should be the last operand");
+                    writeln!(f, "    {operands}{label}")?
+                },
+                None => writeln!(f, "    {text}")?,
+            }
+        }
+        if let Some(label) = labels.get(&(program.len() as i64)) {
+            writeln!(f, "{label}:")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn jump_annotation<I>(program: &Program<I>, ip: usize) -> Option<String>
+where
+    I: InstructionSet,
+{
+    let jump = program.instructions[ip].jump()?;
+    let annotation = match (jump.is_taken(), jump.target(ip as i64)) {
+        (Some(false), _) => "never taken".into(),
+        (_, None) => "-> ?".into(),
+        (_, Some(target)) if program.get(target).is_none() => format!("-> {target} (exit)"),
+        (_, Some(target)) => format!("-> {target}"),
+    };
+    Some(annotation)
+}
+
+impl<I> FromStr for Program<I>
+where
+    I: InstructionSet,
+{
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut names = RegisterNames::default();
+        let instructions = read_instructions(s, &mut names)?;
+        Ok(Self { instructions, names })
+    }
+}
+
+/// Virtual machine executing a duet [`Program`] for a given [`InstructionSet`].
+///
+/// The machine halts when its instruction pointer moves outside the program;
+/// see [`InstructionResult::Exited`].
+///
+/// Executed steps can optionally be recorded in a [`TraceSink`]; see [`Vm::record`].
+/// They can also be profiled; see [`Vm::start_profiling`], and undone; see [`Vm::record_history`].
+#[derive(Debug)]
+pub struct Vm<I>
+where
+    I: InstructionSet,
+{
+    program: Program<I>,
+    registers: Registers,
+    ip: i64,
+    context: I::Context,
+    steps: u64,
+    step_limit: Option<u64>,
+    seen_states: Option<HashMap<(i64, Registers, u64), u64>>,
+    recorder: Option<Recorder>,
+    profile: Option<Profile>,
+    history: Option<History>,
+    compiled: Option<CompiledProgram<I::Context>>,
+}
+
+#[derive(Debug)]
+struct Recorder {
+    id: usize,
+    sink: Box<dyn TraceSink>,
+
+    /// Indices of the registers set from outside the program since the last recorded step.
+    unrecorded: BTreeSet<usize>,
+}
+
+impl<I> Vm<I>
+where
+    I: InstructionSet,
+{
+    pub fn new(program: Program<I>, context: I::Context) -> Self {
+        Self {
+            registers: Registers::new(&program.names),
+            program,
+            ip: 0,
+            context,
+            steps: 0,
+            step_limit: None,
+            seen_states: None,
+            recorder: None,
+            profile: None,
+            history: None,
+            compiled: None,
+        }
+    }
+
+    /// Limits the total number of steps the machine can execute. Once the limit is reached,
+    /// executing returns a [`VmError::StepLimitExceeded`] error.
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    pub fn step_limit(&self) -> Option<u64> {
+        self.step_limit
+    }
+
+    /// Sets how arithmetic instructions handle overflows; see [`Arithmetic`].
+    pub fn set_arithmetic(&mut self, arithmetic: Arithmetic) {
+        self.registers.set_arithmetic(arithmetic);
+    }
+
+    pub fn arithmetic(&self) -> Arithmetic {
+        self.registers.arithmetic()
+    }
+
+    /// Remembers every state (instruction pointer, registers and
+    /// [context](InstructionSet::hash_context)) the machine goes through from now on. If a state
+    /// is reached twice, the machine can never exit and executing returns a
+    /// [`VmError::InfiniteLoop`] error.
+    ///
+    /// States in which the machine is [stalled](InstructionResult::is_stalled) are not remembered,
+    /// since another machine might unblock it. Remembered states are forgotten whenever a value is
+    /// [received](InstructionResult::Received), since received values can depend on other machines.
+    ///
+    /// Detection is heuristic: the context is only remembered by its hash, so a hash collision can
+    /// report a loop that does not exist. Every distinct state is kept until a value is received,
+    /// so memory grows with the number of steps executed; combine with [`Vm::set_step_limit`] for
+    /// long-running programs.
+    pub fn detect_infinite_loops(&mut self, detect: bool) {
+        self.seen_states = detect.then(HashMap::new);
+    }
+
+    /// Records every step executed from now on in `sink`, identifying the machine as program `id`.
+    ///
+    /// Using the same sink for multiple machines (e.g. via `Rc<RefCell<_>>`) records their steps
+    /// in the order they are executed. The values of all registers are recorded before the first
+    /// step, and registers set from outside the program (e.g. with [`Vm::set_register`]) are
+    /// recorded before the next step.
+    pub fn record<S>(&mut self, id: usize, sink: S)
+    where
+        S: TraceSink + 'static,
+    {
+        let unrecorded = (0..self.program.names.len()).collect();
+        self.recorder = Some(Recorder { id, sink: Box::new(sink), unrecorded });
+    }
+
+    /// Marks registers set from outside the program, so that their values are recorded
+    /// before the next step. `None` marks all registers.
+    fn set_externally(&mut self, index: Option<usize>) {
+        if let Some(recorder) = &mut self.recorder {
+            match index {
+                Some(index) => recorder.unrecorded.insert(index),
+                None => {
+                    recorder.unrecorded = (0..self.program.names.len()).collect();
+                    true
+                },
+            };
+        }
+    }
+
+    /// Stops recording steps, returning the sink that was used, if any.
+    pub fn stop_recording(&mut self) -> Option<Box<dyn TraceSink>> {
+        self.recorder.take().map(|recorder| recorder.sink)
+    }
+
+    /// Profiles every step executed from now on: see [`Profile`].
+    pub fn start_profiling(&mut self) {
+        self.profile = Some(Profile::new(self.program.len()));
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Stops profiling, returning the collected profile, if any.
+    pub fn stop_profiling(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    /// Records the changes made by every step executed from now on, so that they can be
+    /// undone with [`Vm::step_back`]. At most `capacity` steps are remembered, if specified.
+    pub fn record_history(&mut self, capacity: Option<usize>) {
+        self.history = Some(History::new(capacity));
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Stops recording the history, returning it, if any.
+    pub fn stop_history(&mut self) -> Option<History> {
+        self.history.take()
+    }
+
+    /// Executes the instruction at the current instruction pointer, then moves to the next one.
+    pub fn execute_next(&mut self) -> Result<InstructionResult, anyhow::Error> {
+        if self.is_monitored() {
+            return self.execute_next_monitored();
+        }
+
+        let Some(instruction) = self.program.get(self.ip) else {
+            return Ok(InstructionResult::Exited);
+        };
+        let result = match self
+            .compiled
+            .as_ref()
+            .and_then(|compiled| compiled.get(self.ip))
+        {
+            Some(compiled) => compiled(&mut self.registers, &mut self.context)?,
+            None => instruction.execute(&mut self.registers, &mut self.context)?,
+        };
+
+        self.ip = self.ip.saturating_add(result.jmp_offset());
+        self.steps += 1;
+        Ok(result)
+    }
+
+    /// Whether steps need to be checked or recorded.
+    fn is_monitored(&self) -> bool {
+        self.step_limit.is_some()
+            || self.seen_states.is_some()
+            || self.recorder.is_some()
+            || self.profile.is_some()
+            || self.history.is_some()
+    }
+
+    /// Version of [`execute_next`](Vm::execute_next) that also enforces the step limit,
+    /// detects infinite loops, records steps, profiles them and records their history.
+    /// Kept separate so that the common case stays small.
+    #[inline(never)]
+    fn execute_next_monitored(&mut self) -> Result<InstructionResult, anyhow::Error> {
+        let Some(instruction) = self.program.get(self.ip) else {
+            return Ok(InstructionResult::Exited);
+        };
+
+        if let Some(limit) = self.step_limit.filter(|&limit| self.steps >= limit) {
+            return Err(VmError::StepLimitExceeded { limit }.into());
+        }
+        let state = match &self.seen_states {
+            Some(seen_states) => {
+                let mut hasher = DefaultHasher::new();
+                I::hash_context(&self.context, &mut hasher);
+                let state = (self.ip, self.registers.clone(), hasher.finish());
+                if let Some(&start) = seen_states.get(&state) {
+                    return Err(VmError::InfiniteLoop { start, length: self.steps - start }.into());
+                }
+                Some(state)
+            },
+            None => None,
+        };
+
+        let before =
+            (self.recorder.is_some() || self.history.is_some()).then(|| self.registers.clone());
+        let started = self.profile.as_ref().map(|_| Instant::now());
+        let result = match self
+            .compiled
+            .as_ref()
+            .and_then(|compiled| compiled.get(self.ip))
+        {
+            Some(compiled) => compiled(&mut self.registers, &mut self.context)?,
+            None => instruction.execute(&mut self.registers, &mut self.context)?,
+        };
+        if let (Some(profile), Some(started)) = (&mut self.profile, started) {
+            profile.record(self.ip as usize, &result, started.elapsed());
+        }
+
+        if let (Some(seen_states), Some(state)) = (&mut self.seen_states, state) {
+            if result.received().is_some() {
+                seen_states.clear();
+            } else if !result.is_stalled() {
+                seen_states.insert(state, self.steps);
+            }
+        }
+
+        if let (Some(history), Some(before)) = (&mut self.history, &before) {
+            history.push(Delta::new(self.ip, result, before, &self.registers));
+        }
+
+        // Finish the step even if the sink fails, so the machine is left in a consistent state.
+        let mut recorded = Ok(());
+        if let (Some(recorder), Some(before)) = (&mut self.recorder, before) {
+            let names = &self.program.names.registers;
+            if !recorder.unrecorded.is_empty() {
+                let unrecorded = std::mem::take(&mut recorder.unrecorded);
+                recorded = recorder.sink.record_registers(RegisterValues {
+                    program: recorder.id,
+                    step: self.steps,
+                    registers: unrecorded
+                        .iter()
+                        .map(|&index| (names[index].to_string(), before.values[index]))
+                        .collect(),
+                    big_registers: unrecorded
+                        .iter()
+                        .filter_map(|&index| {
+                            let value = before.big.get(&index)?;
+                            Some((names[index].to_string(), value.to_string()))
+                        })
+                        .collect(),
+                });
+            }
+
+            let big =
+                |registers: &Registers, index| registers.big.get(&index).map(ToString::to_string);
+            let writes = self
+                .registers
+                .changes(&before)
+                .map(|(index, old, new)| RegisterWrite {
+                    register: names[index].to_string(),
+                    old,
+                    new,
+                    big_old: big(&before, index),
+                    big_new: big(&self.registers, index),
+                })
+                .collect();
+            let step = TraceStep {
+                program: recorder.id,
+                step: self.steps,
+                ip: self.ip,
+                instruction: instruction.to_string(),
+                result,
+                writes,
+            };
+            recorded = recorded.and_then(|_| recorder.sink.record(step));
+        }
+
+        self.ip = self.ip.saturating_add(result.jmp_offset());
+        self.steps += 1;
+        recorded?;
+        Ok(result)
+    }
+
+    /// Executes instructions until the machine [stalls](InstructionResult::is_stalled),
+    /// returning the last result.
+    pub fn execute(&mut self) -> Result<InstructionResult, anyhow::Error> {
+        if let (Some(compiled), false) = (&self.compiled, self.is_monitored()) {
+            while let Some(instruction) = compiled.get(self.ip) {
+                let result = instruction(&mut self.registers, &mut self.context)?;
+                self.ip = self.ip.saturating_add(result.jmp_offset());
+                self.steps += 1;
+                if result.is_stalled() {
+                    return Ok(result);
+                }
+            }
+            return Ok(InstructionResult::Exited);
+        }
+
+        loop {
+            let result = self.execute_next()?;
+            if result.is_stalled() {
+                return Ok(result);
+            }
+        }
+    }
+
+    pub fn program(&self) -> &Program<I> {
+        &self.program
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn ip(&self) -> i64 {
+        self.ip
+    }
+
+    /// Returns the number of steps executed so far (including steps that returned
+    /// [`InstructionResult::Waiting`]).
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn context(&self) -> &I::Context {
+        &self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut I::Context {
+        &mut self.context
+    }
+
+    /// Returns the value of the register with the given name (`0` if the program does not use it).
+    pub fn register(&self, name: &str) -> i64 {
+        self.program
+            .names
+            .get(name)
+            .map(|register| self.registers.get(&register))
+            .unwrap_or_default()
+    }
+
+    /// Sets the value of the register with the given name.
+    ///
+    /// Registers not used by the program are ignored, since they cannot affect its execution.
+    pub fn set_register(&mut self, name: &str, value: i64) {
+        if let Some(register) = self.program.names.get(name) {
+            self.registers.set(&register, value);
+            self.set_externally(Some(register.index));
+        }
+    }
+}
+
+impl<I> Vm<I>
+where
+    I: Compile,
+{
+    /// Compiles the program to closures (see [`Compile`]), so that further steps do not
+    /// need to decode instructions and their operands.
+    pub fn compile(&mut self) {
+        self.compiled = Some(CompiledProgram::new(&self.program.instructions));
+    }
+}
+
+pub fn read_register<'a, I>(
+    parts: &mut I,
+    names: &mut RegisterNames,
+) -> Result<Register, anyhow::Error>
 where
     I: Iterator<Item = &'a str>,
 {
-    parts
-        .next()
-        .with_context(|| "missing register name")?
-        .chars()
-        .next()
-        .with_context(|| "empty register name")
+    names.intern(parts.next().with_context(|| "missing register name")?)
 }
 
-pub fn read_value<'a, I>(parts: &mut I) -> Result<Value, anyhow::Error>
+pub fn read_value<'a, I>(parts: &mut I, names: &mut RegisterNames) -> Result<Value, anyhow::Error>
 where
     I: Iterator<Item = &'a str>,
 {
-    parts.next().with_context(|| "missing value")?.parse()
+    Value::parse(parts.next().with_context(|| "missing value")?, names)
+}
+
+/// Parses the instructions of a program, one per line, interning their registers in `names`.
+///
+/// Instructions can be preceded by labels (`loop:`), either on the same line or on their own line.
+/// Labels can then be used as jump offsets (`jnz g loop`); they are resolved to relative offsets.
+/// A label can also appear after the last instruction, to jump out of the program.
+/// Labels share their namespace with registers, so a label cannot be used as a register.
+pub fn read_instructions<T>(s: &str, names: &mut RegisterNames) -> Result<Vec<T>, anyhow::Error>
+where
+    T: InstructionSet,
+{
+    let (lines, labels) = read_labels(s)?;
+
+    lines
+        .into_iter()
+        .enumerate()
+        .map(|(ip, line)| {
+            resolve_labels(line, ip as i64, &labels, names)
+                .with_context(|| format!("invalid instruction: {line}"))
+        })
+        .collect()
 }
 
-pub fn read_instructions<T>(s: &str) -> Result<Vec<T>, anyhow::Error>
+/// Splits `s` into instruction lines, collecting labels along with the instruction
+/// pointer they refer to.
+fn read_labels(s: &str) -> Result<(Vec<&str>, HashMap<&str, i64>), anyhow::Error> {
+    let mut lines = Vec::new();
+    let mut labels = HashMap::new();
+
+    for line in s.lines() {
+        let mut line = line.trim();
+        while let Some((label, rest)) = line.split_once(':') {
+            let label = label.trim();
+            ensure!(RegisterNames::is_valid_name(label), "invalid label: {label}");
+            ensure!(labels.insert(label, lines.len() as i64).is_none(), "duplicate label: {label}");
+            line = rest.trim();
+        }
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+
+    Ok((lines, labels))
+}
+
+/// Parses the instruction at `ip`, replacing its jump target label (if any) with the
+/// relative offset.
+fn resolve_labels<T>(
+    line: &str,
+    ip: i64,
+    labels: &HashMap<&str, i64>,
+    names: &mut RegisterNames,
+) -> Result<T, anyhow::Error>
 where
-    T: FromStr<Err = anyhow::Error>,
+    T: InstructionSet,
 {
-    s.lines().map(str::parse).collect()
+    let used_labels = line
+        .split_whitespace()
+        .skip(1)
+        .filter(|token| labels.contains_key(token))
+        .collect_vec();
+    let Some(&label) = used_labels.first() else {
+        return T::parse(line, names);
+    };
+
+    // Parse the instruction with the label as a register first, to make sure the label
+    // is used as the jump offset and nowhere else.
+    let unresolved = T::parse(line, &mut names.clone())?;
+    let is_target = matches!(
+        unresolved.jump(),
+        Some(Jump { offset: Value::Register(offset), .. }) if offset.name() == label
+    );
+    ensure!(is_target && used_labels.len() == 1, "label {label} can only be used as a jump offset");
+
+    let offset = (labels[label] - ip).to_string();
+    let resolved = line
+        .split_whitespace()
+        .map(|token| if token == label { offset.as_str() } else { token })
+        .join(" ");
+    T::parse(&resolved, names)
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::day_18::{self, DuetInterpreter};
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(super) enum TestInstruction {
+        Add(Register, Value),
+        Jnz(Value, Value),
+    }
+
+    impl ParseInstruction for TestInstruction {
+        fn parse(s: &str, names: &mut RegisterNames) -> Result<Self, anyhow::Error> {
+            let mut parts = s.split_whitespace();
+            match parts.next() {
+                Some("add") => {
+                    Ok(Self::Add(read_register(&mut parts, names)?, read_value(&mut parts, names)?))
+                },
+                Some("jnz") => {
+                    Ok(Self::Jnz(read_value(&mut parts, names)?, read_value(&mut parts, names)?))
+                },
+                _ => Err(anyhow!("invalid instruction: {s}")),
+            }
+        }
+    }
+
+    impl InstructionSet for TestInstruction {
+        type Context = usize;
+
+        fn execute(
+            &self,
+            registers: &mut Registers,
+            context: &mut usize,
+        ) -> Result<InstructionResult, anyhow::Error> {
+            *context += 1;
+            match self {
+                Self::Add(register, value) => {
+                    registers.set(register, registers.get(register) + value.get(registers))
+                },
+                Self::Jnz(value, offset) if value.get(registers) != 0 => {
+                    return Ok(InstructionResult::JmpOffset(offset.get(registers)));
+                },
+                Self::Jnz(..) => {},
+            }
+            Ok(InstructionResult::Unit)
+        }
+
+        fn jump(&self) -> Option<Jump<'_>> {
+            match self {
+                Self::Jnz(value, offset) => {
+                    Some(Jump { value, condition: JumpCondition::NonZero, offset })
+                },
+                _ => None,
+            }
+        }
+    }
+
+    impl fmt::Display for TestInstruction {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Add(register, value) => write!(f, "add {register} {value}"),
+                Self::Jnz(value, offset) => write!(f, "jnz {value} {offset}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_register_names() {
+        let mut names = RegisterNames::default();
+
+        let a = names.intern("a").unwrap();
+        let ab = names.intern("ab").unwrap();
+        let counter = names.intern("loop_counter2").unwrap();
+        assert_eq!((0, 1, 2), (a.index(), ab.index(), counter.index()));
+        assert_eq!(a, names.intern("a").unwrap());
+        assert_eq!(Some(ab.clone()), names.get("ab"));
+        assert_eq!(None, names.get("b"));
+        assert_eq!(3, names.len());
+
+        assert!(names.intern("").is_err());
+        assert!(names.intern("2a").is_err());
+        assert!(names.intern("a-b").is_err());
+        assert_eq!(3, names.len());
+
+        let mut registers = Registers::new(&names);
+        registers.set(&ab, 42);
+        assert_eq!(0, registers.get(&a));
+        assert_eq!(42, registers.get(&ab));
+    }
+
+    #[test]
+    fn test_value() {
+        let mut names = RegisterNames::default();
+
+        assert_eq!(Value::Number(-17), Value::parse("-17", &mut names).unwrap());
+        let Value::Register(ab) = Value::parse("ab", &mut names).unwrap() else {
+            panic!("expected register");
+        };
+        assert_eq!("ab", ab.name());
+        assert!(Value::parse("-a", &mut names).is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let program: Program<day_18::Instruction> =
+            "set a 9223372036854775807\nadd a 2\nmul a a\nset b a\nmod b -7\njgz a 2\nset c 1"
+                .parse()
+                .unwrap();
+        let run = |arithmetic, compile| {
+            let mut vm = DuetInterpreter::for_part_1(program.clone());
+            vm.set_arithmetic(arithmetic);
+            if compile {
+                vm.compile();
+            }
+            vm.execute().map(|_| vm)
+        };
+
+        for compile in [false, true] {
+            let err = run(Arithmetic::Checked, compile).unwrap_err();
+            assert_eq!("arithmetic overflow in 9223372036854775807 + 2", err.to_string());
+
+            let vm = run(Arithmetic::Wrapping, compile).unwrap();
+            assert_eq!((1, 1, 0), (vm.register("a"), vm.register("b"), vm.register("c")));
+
+            let vm = run(Arithmetic::BigInt, compile).unwrap();
+            let a = vm.program().names().get("a").unwrap();
+            assert_eq!(i64::MAX, vm.register("a"));
+            assert_eq!(
+                "85070591730234615884290395931651604481",
+                vm.registers().get_big(&a).to_string()
+            );
+            assert!(vm.registers().get_exact(&a).is_err());
+            assert_eq!((4, 0), (vm.register("b"), vm.register("c")));
+        }
+
+        for arithmetic in [Arithmetic::Checked, Arithmetic::Wrapping, Arithmetic::BigInt] {
+            let program: Program<day_18::Instruction> = "set a 5\nmod a b".parse().unwrap();
+            let mut vm = DuetInterpreter::for_part_1(program);
+            vm.set_arithmetic(arithmetic);
+            assert_eq!("modulo by zero", vm.execute().unwrap_err().to_string());
+        }
+    }
+
+    #[test]
+    fn test_vm() {
+        let program: Program<TestInstruction> =
+            "add a 3\nadd b 2\nadd a -1\njnz a -2".parse().unwrap();
+        let mut vm = Vm::new(program, 0);
+
+        assert_eq!(InstructionResult::Exited, vm.execute().unwrap());
+        assert_eq!(4, vm.ip());
+        assert_eq!(0, vm.register("a"));
+        assert_eq!(6, vm.register("b"));
+        assert_eq!(0, vm.register("c"));
+        assert_eq!(10, *vm.context());
+
+        assert_eq!(InstructionResult::Exited, vm.execute_next().unwrap());
+        assert_eq!(10, *vm.context());
+
+        let program: Program<TestInstruction> = "add a 1\njnz a -5".parse().unwrap();
+        let mut vm = Vm::new(program, 0);
+        assert_eq!(InstructionResult::Exited, vm.execute().unwrap());
+        assert_eq!(-4, vm.ip());
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let program: Program<TestInstruction> = "add a 1\njnz 1 -1".parse().unwrap();
+        let mut vm = Vm::new(program, 0);
+        vm.set_step_limit(Some(10));
+
+        let err = vm.execute().unwrap_err();
+        assert_eq!(Some(&VmError::StepLimitExceeded { limit: 10 }), err.downcast_ref());
+        assert_eq!("step limit of 10 exceeded", err.to_string());
+        assert_eq!(10, vm.steps());
+        assert_eq!(5, vm.register("a"));
+    }
+
+    #[test]
+    fn test_infinite_loop() {
+        let program: Program<TestInstruction> =
+            "add b 1\nadd a 1\nadd a -1\njnz 1 -2".parse().unwrap();
+        let mut vm = Vm::new(program, 0);
+        vm.detect_infinite_loops(true);
+
+        let err = vm.execute().unwrap_err();
+        assert_eq!(Some(&VmError::InfiniteLoop { start: 1, length: 3 }), err.downcast_ref());
+        assert_eq!(4, vm.steps());
+        assert_eq!(1, vm.ip());
+
+        let program: Program<TestInstruction> =
+            "add a 3\nadd b 2\nadd a -1\njnz a -2".parse().unwrap();
+        let mut vm = Vm::new(program, 0);
+        vm.detect_infinite_loops(true);
+        assert_eq!(InstructionResult::Exited, vm.execute().unwrap());
+    }
+
+    #[test]
+    fn test_display() {
+        let text = "add a 3\nadd loop_b -2\njnz a -1\njnz 0 a\njnz 1 8\njnz a x";
+        let program: Program<TestInstruction> = text.parse().unwrap();
+        assert_eq!(text, program.to_string());
+        assert_eq!(program, program.to_string().parse().unwrap());
+
+        let expected = "0: add a 3\n\
+                        1: add loop_b -2\n\
+                        2: jnz a -1       ; -> 1\n\
+                        3: jnz 0 a        ; never taken\n\
+                        4: jnz 1 8        ; -> 12 (exit)\n\
+                        5: jnz a x        ; -> ?\n";
+        assert_eq!(expected, program.listing().to_string());
+    }
+
+    #[test]
+    fn test_labels() {
+        let text = "start: add a 3\n\
+                    loop:\n\
+                    \tadd b 2\n\
+                    \n\
+                    add a -1\n\
+                    jnz a loop\n\
+                    jnz 1 end\n\
+                    jnz 1 start\n\
+                    end:";
+        let program: Program<TestInstruction> = text.parse().unwrap();
+        let expected: Program<TestInstruction> =
+            "add a 3\nadd b 2\nadd a -1\njnz a -2\njnz 1 2\njnz 1 -5"
+                .parse()
+                .unwrap();
+        assert_eq!(expected, program);
+        assert_eq!(2, program.names().len());
+
+        let errors = [
+            ("loop: add a 1\nloop: jnz a loop", "duplicate label: loop"),
+            ("2x: add a 1", "invalid label: 2x"),
+            ("a: add a 1\njnz 1 a", "label a can only be used as a jump offset"),
+            ("x: jnz x x", "label x can only be used as a jump offset"),
+        ];
+        for (text, expected) in errors {
+            let err = text.parse::<Program<TestInstruction>>().unwrap_err();
+            assert_eq!(expected, err.root_cause().to_string());
+        }
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let text = "add l1 3\nadd b 2\nadd l1 -1\njnz l1 -2\njnz 0 -4\njnz a x\njnz 1 2\njnz 1 8";
+        let program: Program<TestInstruction> = text.parse().unwrap();
+
+        let expected = "    add l1 3\n\
+                        l1_:\n\
+                        \x20   add b 2\n\
+                        \x20   add l1 -1\n\
+                        \x20   jnz l1 l1_\n\
+                        \x20   jnz 0 -4\n\
+                        \x20   jnz a x\n\
+                        \x20   jnz 1 l8\n\
+                        \x20   jnz 1 8\n\
+                        l8:\n";
+        assert_eq!(expected, program.disassemble().to_string());
+        assert_eq!(program, program.disassemble().to_string().parse().unwrap());
+    }
 }
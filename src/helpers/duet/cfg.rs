@@ -0,0 +1,492 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use itertools::Itertools;
+
+use crate::helpers::duet::{InstructionSet, Program};
+
+/// Destination of an [`Edge`] of a [`ControlFlowGraph`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Target {
+    /// Basic block with the given ID.
+    Block(usize),
+
+    /// Outside of the program: the machine exits.
+    Exit,
+
+    /// Target of a jump whose offset is stored in a register.
+    Unknown,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EdgeKind {
+    /// Execution continues with the next instruction.
+    FallThrough,
+
+    /// A jump is always taken.
+    Jump,
+
+    /// A jump is taken only when its condition is met.
+    ConditionalJump,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Edge {
+    pub target: Target,
+    pub kind: EdgeKind,
+}
+
+/// Sequence of instructions that always execute one after the other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    /// Instruction pointer of the first instruction in the block.
+    pub start: usize,
+
+    /// Instruction pointer after the last instruction in the block.
+    pub end: usize,
+
+    pub successors: Vec<Edge>,
+}
+
+impl BasicBlock {
+    pub fn ips(&self) -> impl Iterator<Item = usize> {
+        self.start..self.end
+    }
+}
+
+/// Natural loop of a [`ControlFlowGraph`], identified by a back edge to its header.
+///
+/// Loops with the same header are merged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    /// ID of the block that dominates all blocks of the loop.
+    pub header: usize,
+
+    /// IDs of all blocks in the loop, including the header.
+    pub blocks: BTreeSet<usize>,
+
+    /// Index of the innermost loop containing this one, if any.
+    pub parent: Option<usize>,
+
+    /// Number of loops containing this one.
+    pub depth: usize,
+}
+
+/// Control-flow graph of a [`Program`], made of [basic blocks](BasicBlock).
+///
+/// Blocks are identified by their index, in program order; the entry block is always `0`.
+/// Jumps whose offset is stored in a register lead to [`Target::Unknown`]; since they
+/// could jump anywhere, they are ignored when detecting loops.
+#[derive(Debug)]
+pub struct ControlFlowGraph<'a, I> {
+    program: &'a Program<I>,
+    blocks: Vec<BasicBlock>,
+    loops: Vec<Loop>,
+}
+
+impl<'a, I> ControlFlowGraph<'a, I>
+where
+    I: InstructionSet,
+{
+    pub fn new(program: &'a Program<I>) -> Self {
+        let blocks = Self::build_blocks(program);
+        let loops = find_loops(&blocks);
+        Self { program, blocks, loops }
+    }
+
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+
+    /// Returns the ID of the block containing the instruction at `ip`, if any.
+    pub fn block_at(&self, ip: usize) -> Option<usize> {
+        let id = self.blocks.partition_point(|block| block.end <= ip);
+        (id < self.blocks.len()).then_some(id)
+    }
+
+    /// Returns the IDs of the blocks that can jump or fall through to block `id`.
+    pub fn predecessors(&self, id: usize) -> impl Iterator<Item = usize> + '_ {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter_map(move |(predecessor, block)| {
+                block
+                    .successors
+                    .iter()
+                    .any(|edge| edge.target == Target::Block(id))
+                    .then_some(predecessor)
+            })
+    }
+
+    /// Returns the natural loops of the program, outer loops first.
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops
+    }
+
+    /// Returns a [Graphviz](https://graphviz.org/) DOT representation of the graph,
+    /// with loops drawn as nested clusters.
+    pub fn dot(&self) -> Dot<'_, 'a, I> {
+        Dot(self)
+    }
+
+    fn build_blocks(program: &Program<I>) -> Vec<BasicBlock> {
+        let len = program.len();
+        let jumps = program
+            .instructions()
+            .iter()
+            .enumerate()
+            .filter_map(|(ip, instruction)| {
+                let jump = instruction.jump()?;
+                (jump.is_taken() != Some(false))
+                    .then(|| (ip, jump.is_taken(), jump.target(ip as i64)))
+            })
+            .collect_vec();
+
+        let mut leaders: BTreeSet<usize> = BTreeSet::from([0]);
+        for &(ip, _, target) in &jumps {
+            leaders.insert(ip + 1);
+            if let Some(target) = target.filter(|&target| (0..len as i64).contains(&target)) {
+                leaders.insert(target as usize);
+            }
+        }
+        leaders.retain(|&leader| leader < len);
+
+        let starts = leaders.into_iter().collect_vec();
+        let block_of = |target: i64| match usize::try_from(target) {
+            Ok(target) if target < len => {
+                Target::Block(starts.partition_point(|&start| start <= target) - 1)
+            },
+            _ => Target::Exit,
+        };
+
+        starts
+            .iter()
+            .enumerate()
+            .map(|(id, &start)| {
+                let end = starts.get(id + 1).copied().unwrap_or(len);
+                let last = end - 1;
+                let mut successors = Vec::new();
+                let fall_through = match jumps.iter().find(|&&(ip, _, _)| ip == last) {
+                    Some(&(_, taken, target)) => {
+                        let kind = match taken {
+                            Some(true) => EdgeKind::Jump,
+                            _ => EdgeKind::ConditionalJump,
+                        };
+                        let target = target.map_or(Target::Unknown, block_of);
+                        successors.push(Edge { target, kind });
+                        taken.is_none()
+                    },
+                    None => true,
+                };
+                if fall_through {
+                    successors
+                        .push(Edge { target: block_of(end as i64), kind: EdgeKind::FallThrough });
+                }
+                BasicBlock { start, end, successors }
+            })
+            .collect()
+    }
+}
+
+/// Computes the dominators of each block, ignoring unknown edges. Blocks unreachable
+/// from the entry block have no dominators.
+fn dominators(blocks: &[BasicBlock]) -> Vec<BTreeSet<usize>> {
+    let successors = |id: usize| {
+        blocks[id]
+            .successors
+            .iter()
+            .filter_map(|edge| match edge.target {
+                Target::Block(target) => Some(target),
+                _ => None,
+            })
+    };
+
+    let mut reachable = BTreeSet::from([0]);
+    let mut stack = vec![0];
+    while let Some(id) = stack.pop() {
+        for successor in successors(id) {
+            if reachable.insert(successor) {
+                stack.push(successor);
+            }
+        }
+    }
+
+    let mut predecessors = vec![Vec::new(); blocks.len()];
+    for &id in &reachable {
+        for successor in successors(id) {
+            predecessors[successor].push(id);
+        }
+    }
+
+    let mut dominators: Vec<BTreeSet<usize>> = (0..blocks.len())
+        .map(|id| match id {
+            0 => BTreeSet::from([0]),
+            id if reachable.contains(&id) => reachable.clone(),
+            _ => BTreeSet::new(),
+        })
+        .collect();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &id in reachable.iter().skip(1) {
+            let mut new = predecessors[id]
+                .iter()
+                .map(|&predecessor| dominators[predecessor].clone())
+                .reduce(|a, b| &a & &b)
+                .unwrap_or_default();
+            new.insert(id);
+            if new != dominators[id] {
+                dominators[id] = new;
+                changed = true;
+            }
+        }
+    }
+
+    dominators
+}
+
+fn find_loops(blocks: &[BasicBlock]) -> Vec<Loop> {
+    if blocks.is_empty() {
+        return Vec::new();
+    }
+    let dominators = dominators(blocks);
+
+    let mut loops: Vec<Loop> = Vec::new();
+    for (source, block) in blocks.iter().enumerate() {
+        for edge in &block.successors {
+            let Target::Block(header) = edge.target else {
+                continue;
+            };
+            if !dominators[source].contains(&header) {
+                continue;
+            }
+
+            // Natural loop of the back edge: header plus all blocks reaching the source
+            // without going through the header.
+            let mut body = BTreeSet::from([header, source]);
+            let mut stack = vec![source];
+            while let Some(id) = stack.pop() {
+                if id == header {
+                    continue;
+                }
+                for (predecessor, block) in blocks.iter().enumerate() {
+                    let is_predecessor = block
+                        .successors
+                        .iter()
+                        .any(|edge| edge.target == Target::Block(id));
+                    if is_predecessor
+                        && !dominators[predecessor].is_empty()
+                        && body.insert(predecessor)
+                    {
+                        stack.push(predecessor);
+                    }
+                }
+            }
+
+            match loops.iter_mut().find(|l| l.header == header) {
+                Some(l) => l.blocks.extend(body),
+                None => loops.push(Loop { header, blocks: body, parent: None, depth: 0 }),
+            }
+        }
+    }
+
+    // Outer loops contain more blocks, so they come first.
+    loops.sort_by_key(|l| (usize::MAX - l.blocks.len(), l.header));
+    for i in 0..loops.len() {
+        let parent = (0..i)
+            .rev()
+            .find(|&j| loops[i].blocks.is_subset(&loops[j].blocks));
+        loops[i].parent = parent;
+        loops[i].depth = parent.map_or(0, |parent| loops[parent].depth + 1);
+    }
+    loops
+}
+
+/// DOT representation of a [`ControlFlowGraph`]. See [`ControlFlowGraph::dot`].
+#[derive(Debug)]
+pub struct Dot<'g, 'a, I>(&'g ControlFlowGraph<'a, I>);
+
+impl<'g, 'a, I> Dot<'g, 'a, I>
+where
+    I: InstructionSet,
+{
+    fn write_loop(&self, f: &mut fmt::Formatter<'_>, index: usize) -> fmt::Result {
+        let cfg = self.0;
+        let l = &cfg.loops[index];
+        let indent = "    ".repeat(l.depth + 1);
+        let children = (0..cfg.loops.len())
+            .filter(|&child| cfg.loops[child].parent == Some(index))
+            .collect_vec();
+
+        writeln!(f, "{indent}subgraph cluster_loop_{index} {{")?;
+        writeln!(f, "{indent}    label=\"loop {index}\";")?;
+        for &child in &children {
+            self.write_loop(f, child)?;
+        }
+        let nested: BTreeSet<usize> = children
+            .iter()
+            .flat_map(|&child| cfg.loops[child].blocks.iter().copied())
+            .collect();
+        for id in l.blocks.difference(&nested) {
+            writeln!(f, "{indent}    b{id};")?;
+        }
+        writeln!(f, "{indent}}}")
+    }
+}
+
+impl<'g, 'a, I> fmt::Display for Dot<'g, 'a, I>
+where
+    I: InstructionSet,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cfg = self.0;
+        let instructions = cfg.program.instructions();
+
+        writeln!(f, "digraph program {{")?;
+        writeln!(f, "    node [shape=box, fontname=\"monospace\"];")?;
+        for (id, block) in cfg.blocks.iter().enumerate() {
+            let label: String = block
+                .ips()
+                .map(|ip| format!("{ip}: {}\\l", instructions[ip]))
+                .collect();
+            writeln!(f, "    b{id} [label=\"{label}\"];")?;
+        }
+
+        let targets: BTreeSet<Target> = cfg
+            .blocks
+            .iter()
+            .flat_map(|block| block.successors.iter().map(|edge| edge.target))
+            .collect();
+        if targets.contains(&Target::Exit) {
+            writeln!(f, "    exit [shape=doublecircle, label=\"exit\"];")?;
+        }
+        if targets.contains(&Target::Unknown) {
+            writeln!(f, "    unknown [shape=diamond, label=\"?\"];")?;
+        }
+
+        for index in (0..cfg.loops.len()).filter(|&index| cfg.loops[index].parent.is_none()) {
+            self.write_loop(f, index)?;
+        }
+
+        for (id, block) in cfg.blocks.iter().enumerate() {
+            for edge in &block.successors {
+                let target = match edge.target {
+                    Target::Block(target) => format!("b{target}"),
+                    Target::Exit => "exit".into(),
+                    Target::Unknown => "unknown".into(),
+                };
+                let attributes = match edge.kind {
+                    EdgeKind::FallThrough => " [style=dashed]",
+                    EdgeKind::Jump => "",
+                    EdgeKind::ConditionalJump => " [label=\"taken\"]",
+                };
+                writeln!(f, "    b{id} -> {target}{attributes};")?;
+            }
+        }
+
+        writeln!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+    use crate::day_23;
+    use crate::helpers::duet::tests::TestInstruction;
+    use crate::input::day_23::INPUT;
+
+    fn edge(target: Target, kind: EdgeKind) -> Edge {
+        Edge { target, kind }
+    }
+
+    #[test]
+    fn test_blocks() {
+        let program: Program<TestInstruction> =
+            "add a 3\nadd b 2\nadd a -1\njnz a -2\njnz 0 5\njnz 1 x\nadd c 1"
+                .parse()
+                .unwrap();
+        let cfg = ControlFlowGraph::new(&program);
+
+        let blocks = cfg.blocks();
+        assert_eq!(blocks.len(), 4);
+        assert_eq!((blocks[0].start, blocks[0].end), (0, 1));
+        assert_eq!((blocks[1].start, blocks[1].end), (1, 4));
+        assert_eq!((blocks[2].start, blocks[2].end), (4, 6));
+        assert_eq!((blocks[3].start, blocks[3].end), (6, 7));
+        assert_eq!(blocks[0].successors, [edge(Target::Block(1), EdgeKind::FallThrough)]);
+        assert_eq!(
+            blocks[1].successors,
+            [
+                edge(Target::Block(1), EdgeKind::ConditionalJump),
+                edge(Target::Block(2), EdgeKind::FallThrough),
+            ]
+        );
+        assert_eq!(blocks[2].successors, [edge(Target::Unknown, EdgeKind::Jump)]);
+        assert_eq!(blocks[3].successors, [edge(Target::Exit, EdgeKind::FallThrough)]);
+
+        assert_eq!(cfg.block_at(2), Some(1));
+        assert_eq!(cfg.block_at(6), Some(3));
+        assert_eq!(cfg.block_at(7), None);
+        assert_eq!(cfg.predecessors(1).collect_vec(), [0, 1]);
+        assert_eq!(cfg.predecessors(3).collect_vec(), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_loops() {
+        let program: Program<TestInstruction> =
+            "add a 2\nadd b 2\nadd b -1\njnz b -1\nadd a -1\njnz a -4\njnz c 10"
+                .parse()
+                .unwrap();
+        let cfg = ControlFlowGraph::new(&program);
+
+        let loops = cfg.loops();
+        assert_eq!(loops.len(), 2);
+        assert_eq!(loops[0].header, 1);
+        assert_eq!(loops[0].blocks, BTreeSet::from([1, 2, 3]));
+        assert_eq!((loops[0].parent, loops[0].depth), (None, 0));
+        assert_eq!(loops[1].header, 2);
+        assert_eq!(loops[1].blocks, BTreeSet::from([2]));
+        assert_eq!((loops[1].parent, loops[1].depth), (Some(0), 1));
+    }
+
+    #[test]
+    fn test_day_23() {
+        let program: Program<day_23::Instruction> = INPUT.parse().unwrap();
+        let cfg = ControlFlowGraph::new(&program);
+
+        let loops = cfg.loops();
+        assert_eq!(loops.iter().map(|l| l.depth).collect_vec(), [0, 1, 2]);
+        assert_eq!(
+            loops
+                .iter()
+                .map(|l| cfg.blocks()[l.header].start)
+                .collect_vec(),
+            [8, 10, 11]
+        );
+    }
+
+    #[test]
+    fn test_dot() {
+        let program: Program<TestInstruction> = "add a 3\nadd a -1\njnz a -1".parse().unwrap();
+        let dot = ControlFlowGraph::new(&program).dot().to_string();
+        assert_eq!(
+            dot,
+            "digraph program {
+    node [shape=box, fontname=\"monospace\"];
+    b0 [label=\"0: add a 3\\l\"];
+    b1 [label=\"1: add a -1\\l2: jnz a -1\\l\"];
+    exit [shape=doublecircle, label=\"exit\"];
+    subgraph cluster_loop_0 {
+        label=\"loop 0\";
+        b1;
+    }
+    b0 -> b1 [style=dashed];
+    b1 -> b1 [label=\"taken\"];
+    b1 -> exit [style=dashed];
+}
+"
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use std::fmt;
+
+use crate::helpers::duet::{
+    InstructionResult, InstructionSet, JumpCondition, Operation, Register, Registers, Value,
+};
+
+/// Instruction compiled to a closure, with its operands already resolved.
+pub type CompiledInstruction<C> =
+    Box<dyn Fn(&mut Registers, &mut C) -> Result<InstructionResult, anyhow::Error>>;
+
+/// [`InstructionSet`] whose instructions can be compiled to closures;
+/// see [`Vm::compile`](crate::helpers::duet::Vm::compile).
+///
+/// Compiled instructions must behave exactly like [`InstructionSet::execute`].
+pub trait Compile: InstructionSet {
+    fn compile(&self) -> CompiledInstruction<Self::Context>;
+}
+
+/// Compiled instructions of a program, indexed by instruction pointer.
+pub struct CompiledProgram<C>(Vec<CompiledInstruction<C>>);
+
+impl<C> CompiledProgram<C> {
+    pub fn new<I>(instructions: &[I]) -> Self
+    where
+        I: Compile<Context = C>,
+    {
+        Self(instructions.iter().map(Compile::compile).collect())
+    }
+
+    pub fn get(&self, ip: i64) -> Option<&CompiledInstruction<C>> {
+        usize::try_from(ip).ok().and_then(|ip| self.0.get(ip))
+    }
+}
+
+impl<C> fmt::Debug for CompiledProgram<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CompiledProgram({} instructions)", self.0.len())
+    }
+}
+
+/// Compiles an instruction that sets `register` to `op(register, value, context)`.
+pub fn update<C, F>(register: &Register, value: &Value, op: F) -> CompiledInstruction<C>
+where
+    C: 'static,
+    F: Fn(i64, i64, &mut C) -> i64 + 'static,
+{
+    let register = register.clone();
+    match value {
+        Value::Number(n) => {
+            let n = *n;
+            Box::new(move |registers, context| {
+                registers.set(&register, op(registers.get(&register), n, context));
+                Ok(InstructionResult::Unit)
+            })
+        },
+        Value::Register(source) => {
+            let source = source.clone();
+            Box::new(move |registers, context| {
+                let value = registers.get(&source);
+                registers.set(&register, op(registers.get(&register), value, context));
+                Ok(InstructionResult::Unit)
+            })
+        },
+    }
+}
+
+/// Compiles an instruction that applies `operation` to `register` and `value`; see
+/// [`Registers::apply`]. `before` is called with the context every time the instruction
+/// is executed.
+pub fn apply<C, F>(
+    register: &Register,
+    operation: Operation,
+    value: &Value,
+    before: F,
+) -> CompiledInstruction<C>
+where
+    C: 'static,
+    F: Fn(&mut C) + 'static,
+{
+    let (register, value) = (register.clone(), value.clone());
+    Box::new(move |registers, context| {
+        before(context);
+        registers.apply(&register, operation, &value)?;
+        Ok(InstructionResult::Unit)
+    })
+}
+
+/// Compiles a relative jump by `offset` when `value` meets `condition`.
+/// `before` is called with the context every time the instruction is executed.
+pub fn jump<C, F>(
+    value: &Value,
+    condition: JumpCondition,
+    offset: &Value,
+    before: F,
+) -> CompiledInstruction<C>
+where
+    C: 'static,
+    F: Fn(&mut C) + 'static,
+{
+    match (value, offset) {
+        (Value::Number(n), Value::Number(offset)) => {
+            let result = jump_result(condition.is_met(*n), *offset);
+            Box::new(move |_, context| {
+                before(context);
+                Ok(result)
+            })
+        },
+        (Value::Register(register), Value::Number(offset)) => {
+            let (register, offset) = (register.clone(), *offset);
+            Box::new(move |registers, context| {
+                before(context);
+                Ok(jump_result(condition.is_met(registers.get(&register)), offset))
+            })
+        },
+        (value, offset) => {
+            let (value, offset) = (value.clone(), offset.clone());
+            Box::new(move |registers, context| {
+                before(context);
+                Ok(jump_result(condition.is_met(value.get(registers)), offset.get(registers)))
+            })
+        },
+    }
+}
+
+fn jump_result(taken: bool, offset: i64) -> InstructionResult {
+    if taken {
+        InstructionResult::JmpOffset(offset)
+    } else {
+        InstructionResult::Unit
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+    use crate::helpers::duet::tests::TestInstruction;
+    use crate::helpers::duet::{Program, Vm};
+
+    impl Compile for TestInstruction {
+        fn compile(&self) -> CompiledInstruction<usize> {
+            match self {
+                Self::Add(register, value) => update(register, value, |a, b, steps| {
+                    *steps += 1;
+                    a + b
+                }),
+                Self::Jnz(value, offset) => {
+                    jump(value, JumpCondition::NonZero, offset, |steps: &mut usize| *steps += 1)
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn test_compiled() {
+        let programs = [
+            "add a 3\nadd b 2\nadd a -1\njnz a -2",
+            "add a 5\nadd b a\nadd c 2\nadd a -1\njnz a -3\njnz 1 2\nadd b 100",
+            "add a 1\nadd o 2\njnz a o\nadd b 1\nadd c b\njnz 0 -5\njnz b 1",
+        ];
+
+        for program in programs {
+            let program: Program<TestInstruction> = program.parse().unwrap();
+            let mut interpreted = Vm::new(program.clone(), 0);
+            let mut compiled = Vm::new(program, 0);
+            compiled.compile();
+
+            assert_eq!(interpreted.execute().unwrap(), compiled.execute().unwrap());
+            assert_eq!(interpreted.registers(), compiled.registers());
+            assert_eq!(interpreted.ip(), compiled.ip());
+            assert_eq!(interpreted.steps(), compiled.steps());
+            assert_eq!(interpreted.context(), compiled.context());
+        }
+    }
+}
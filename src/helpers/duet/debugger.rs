@@ -0,0 +1,735 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Context};
+use itertools::Itertools;
+
+use crate::helpers::duet::history::Reversible;
+use crate::helpers::duet::{InstructionResult, InstructionSet, Vm};
+
+/// Comparison operator used in a [`Condition`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    pub fn compare(&self, a: i64, b: i64) -> bool {
+        match self {
+            Self::Eq => a == b,
+            Self::Ne => a != b,
+            Self::Lt => a < b,
+            Self::Le => a <= b,
+            Self::Gt => a > b,
+            Self::Ge => a >= b,
+        }
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "==" => Self::Eq,
+            "!=" => Self::Ne,
+            "<" => Self::Lt,
+            "<=" => Self::Le,
+            ">" => Self::Gt,
+            ">=" => Self::Ge,
+            s => bail!("invalid comparison: {s}"),
+        })
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            Self::Eq => "==",
+            Self::Ne => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        };
+        write!(f, "{op}")
+    }
+}
+
+/// Condition on the value of a register, in the form `<register> <comparison> <value>`
+/// (e.g. `a >= 3`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub register: String,
+    pub comparison: Comparison,
+    pub value: i64,
+}
+
+impl Condition {
+    pub fn is_met<I>(&self, vm: &Vm<I>) -> bool
+    where
+        I: InstructionSet,
+    {
+        self.comparison
+            .compare(vm.register(&self.register), self.value)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (register, comparison, value) = s
+            .split_whitespace()
+            .collect_tuple()
+            .with_context(|| format!("invalid condition: {s}"))?;
+
+        Ok(Self {
+            register: register.into(),
+            comparison: comparison.parse()?,
+            value: value
+                .parse()
+                .with_context(|| format!("invalid value: {value}"))?,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.register, self.comparison, self.value)
+    }
+}
+
+/// Breakpoint stopping execution before the instruction at `ip` is executed, if `condition`
+/// is met. At least one of the two must be specified.
+///
+/// Breakpoints can be parsed from strings in the form `<ip>`, `<ip> if <condition>` or
+/// `<condition>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub ip: Option<i64>,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    pub fn at(ip: i64) -> Self {
+        Self { ip: Some(ip), condition: None }
+    }
+
+    pub fn when(condition: Condition) -> Self {
+        Self { ip: None, condition: Some(condition) }
+    }
+
+    pub fn is_hit<I>(&self, vm: &Vm<I>) -> bool
+    where
+        I: InstructionSet,
+    {
+        self.ip.map_or(true, |ip| ip == vm.ip())
+            && self
+                .condition
+                .as_ref()
+                .map_or(true, |condition| condition.is_met(vm))
+    }
+}
+
+impl FromStr for Breakpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Some((ip, condition)) = s.split_once(" if ") {
+            return Ok(Self {
+                ip: Some(
+                    ip.trim()
+                        .parse()
+                        .with_context(|| format!("invalid instruction index: {ip}"))?,
+                ),
+                condition: Some(condition.parse()?),
+            });
+        }
+
+        match s.parse() {
+            Ok(ip) => Ok(Self::at(ip)),
+            Err(_) => Ok(Self::when(s.parse()?)),
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.ip, &self.condition) {
+            (Some(ip), Some(condition)) => write!(f, "{ip} if {condition}"),
+            (Some(ip), None) => write!(f, "{ip}"),
+            (None, Some(condition)) => write!(f, "{condition}"),
+            (None, None) => write!(f, "always"),
+        }
+    }
+}
+
+/// Reason why a [`Debugger`] stopped executing the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// A single instruction was executed.
+    Step(InstructionResult),
+
+    /// Breakpoint with the given ID was hit.
+    Breakpoint(usize),
+
+    /// Value of a watched register changed.
+    Watchpoint { register: String, old: i64, new: i64 },
+
+    /// Program [stalled](InstructionResult::is_stalled) and cannot progress.
+    Stalled(InstructionResult),
+
+    /// No step is left to undo when executing backwards; see [`Vm::record_history`].
+    HistoryStart,
+}
+
+/// Debugger controlling the execution of a duet [`Vm`].
+pub struct Debugger<I>
+where
+    I: InstructionSet,
+{
+    vm: Vm<I>,
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    next_breakpoint_id: usize,
+    watchpoints: Vec<String>,
+}
+
+impl<I> Debugger<I>
+where
+    I: InstructionSet,
+{
+    pub fn new(vm: Vm<I>) -> Self {
+        Self { vm, breakpoints: BTreeMap::new(), next_breakpoint_id: 1, watchpoints: Vec::new() }
+    }
+
+    pub fn vm(&self) -> &Vm<I> {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut Vm<I> {
+        &mut self.vm
+    }
+
+    pub fn into_vm(self) -> Vm<I> {
+        self.vm
+    }
+
+    /// Adds a breakpoint, returning its ID.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_breakpoint_id;
+        self.next_breakpoint_id += 1;
+        self.breakpoints.insert(id, breakpoint);
+        id
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> Option<Breakpoint> {
+        self.breakpoints.remove(&id)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .map(|(&id, breakpoint)| (id, breakpoint))
+    }
+
+    /// Watches a register, stopping execution whenever its value changes.
+    pub fn add_watchpoint<S>(&mut self, register: S)
+    where
+        S: Into<String>,
+    {
+        let register = register.into();
+        if !self.watchpoints.contains(&register) {
+            self.watchpoints.push(register);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, register: &str) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|watched| watched != register);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = &str> {
+        self.watchpoints.iter().map(String::as_str)
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> Result<Stop, anyhow::Error> {
+        let watched = self.watched_values();
+
+        let result = self.vm.execute_next()?;
+        if result.is_stalled() {
+            return Ok(Stop::Stalled(result));
+        }
+
+        Ok(self
+            .changed_watchpoint(watched)
+            .unwrap_or(Stop::Step(result)))
+    }
+
+    /// Executes instructions until a breakpoint is hit, a watched register changes
+    /// or the program stalls.
+    ///
+    /// The instruction at the current instruction pointer is always executed, so that
+    /// execution can resume after hitting a breakpoint.
+    pub fn resume(&mut self) -> Result<Stop, anyhow::Error> {
+        loop {
+            match self.step()? {
+                Stop::Step(_) => {},
+                stop => return Ok(stop),
+            }
+
+            if let Some((&id, _)) = self
+                .breakpoints
+                .iter()
+                .find(|(_, breakpoint)| breakpoint.is_hit(&self.vm))
+            {
+                return Ok(Stop::Breakpoint(id));
+            }
+        }
+    }
+
+    fn watched_values(&self) -> Vec<i64> {
+        self.watchpoints
+            .iter()
+            .map(|register| self.vm.register(register))
+            .collect()
+    }
+
+    fn changed_watchpoint(&self, before: Vec<i64>) -> Option<Stop> {
+        self.watchpoints
+            .iter()
+            .zip(before)
+            .map(|(register, old)| (register, old, self.vm.register(register)))
+            .find(|&(_, old, new)| old != new)
+            .map(|(register, old, new)| Stop::Watchpoint { register: register.clone(), old, new })
+    }
+}
+
+impl<I> Debugger<I>
+where
+    I: Reversible,
+{
+    /// Undoes the last executed instruction, if the machine
+    /// [records its history](Vm::record_history).
+    ///
+    /// Watchpoints report values in execution order: `old` is the value before the undone step.
+    pub fn step_back(&mut self) -> Result<Stop, anyhow::Error> {
+        let watched = self.watched_values();
+
+        let Some(delta) = self.vm.step_back()? else {
+            return Ok(Stop::HistoryStart);
+        };
+
+        let stop = match self.changed_watchpoint(watched) {
+            Some(Stop::Watchpoint { register, old, new }) => {
+                Stop::Watchpoint { register, old: new, new: old }
+            },
+            _ => Stop::Step(delta.result),
+        };
+        Ok(stop)
+    }
+
+    /// Undoes instructions until a breakpoint is hit, a watched register changes or
+    /// the start of the history is reached.
+    pub fn reverse(&mut self) -> Result<Stop, anyhow::Error> {
+        loop {
+            match self.step_back()? {
+                Stop::Step(_) => {},
+                stop => return Ok(stop),
+            }
+
+            if let Some((&id, _)) = self
+                .breakpoints
+                .iter()
+                .find(|(_, breakpoint)| breakpoint.is_hit(&self.vm))
+            {
+                return Ok(Stop::Breakpoint(id));
+            }
+        }
+    }
+
+    /// Goes back to the last time `register` changed, stopping right before the instruction
+    /// that changed it. Returns the old and new value of the register.
+    ///
+    /// If the register did not change in the recorded history, the undone instructions are
+    /// executed again (so that the machine is back where it was) and an error is returned.
+    pub fn last_change(&mut self, register: &str) -> Result<(i64, i64), anyhow::Error> {
+        ensure!(self.vm.program().names().get(register).is_some(), "unknown register: {register}");
+
+        let (new, steps) = (self.vm.register(register), self.vm.steps());
+        while self.vm.step_back()?.is_some() {
+            let old = self.vm.register(register);
+            if old != new {
+                return Ok((old, new));
+            }
+        }
+
+        while self.vm.steps() < steps {
+            self.vm.execute_next()?;
+        }
+        bail!("register {register} did not change in the recorded history");
+    }
+}
+
+impl<I> Debugger<I>
+where
+    I: Reversible + fmt::Display,
+    I::Context: fmt::Display,
+{
+    /// Runs an interactive debugging session, reading commands from `input` and writing
+    /// to `output` until `quit` is entered or `input` is exhausted.
+    ///
+    /// Enter `help` for a list of commands.
+    pub fn repl<R, W>(&mut self, input: R, mut output: W) -> Result<(), anyhow::Error>
+    where
+        R: BufRead,
+        W: Write,
+    {
+        self.write_location(&mut output)?;
+        write!(output, "(duet) ")?;
+        output.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            let (command, args) = line
+                .trim()
+                .split_once(char::is_whitespace)
+                .unwrap_or((line.trim(), ""));
+
+            match command {
+                "" => {},
+                "q" | "quit" => return Ok(()),
+                command => {
+                    if let Err(err) = self.execute_command(command, args.trim(), &mut output) {
+                        writeln!(output, "error: {err}")?;
+                    }
+                },
+            }
+
+            write!(output, "(duet) ")?;
+            output.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn execute_command<W>(
+        &mut self,
+        command: &str,
+        args: &str,
+        output: &mut W,
+    ) -> Result<(), anyhow::Error>
+    where
+        W: Write,
+    {
+        match command {
+            "h" | "help" => writeln!(output, "{}", HELP)?,
+            "s" | "step" => {
+                let count = if args.is_empty() { 1 } else { args.parse()? };
+                for _ in 0..count {
+                    let stop = self.step()?;
+                    if !matches!(stop, Stop::Step(_)) {
+                        self.write_stop(&stop, output)?;
+                        break;
+                    }
+                }
+                self.write_location(output)?;
+            },
+            "c" | "continue" => {
+                let stop = self.resume()?;
+                self.write_stop(&stop, output)?;
+                self.write_location(output)?;
+            },
+            "rs" | "rstep" => {
+                let count = if args.is_empty() { 1 } else { args.parse()? };
+                for _ in 0..count {
+                    let stop = self.step_back()?;
+                    if !matches!(stop, Stop::Step(_)) {
+                        self.write_stop(&stop, output)?;
+                        break;
+                    }
+                }
+                self.write_location(output)?;
+            },
+            "rc" | "rcontinue" => {
+                let stop = self.reverse()?;
+                self.write_stop(&stop, output)?;
+                self.write_location(output)?;
+            },
+            "last" => {
+                let (old, new) = self.last_change(args)?;
+                writeln!(output, "{args} changed from {old} to {new} at step {}", self.vm.steps())?;
+                self.write_location(output)?;
+            },
+            "b" | "break" => {
+                let breakpoint: Breakpoint = args.parse()?;
+                let id = self.add_breakpoint(breakpoint.clone());
+                writeln!(output, "breakpoint {id}: {breakpoint}")?;
+            },
+            "d" | "delete" => {
+                let id = args.parse()?;
+                self.remove_breakpoint(id)
+                    .with_context(|| format!("no breakpoint {id}"))?;
+            },
+            "w" | "watch" => {
+                if self.vm.program().names().get(args).is_none() {
+                    bail!("unknown register: {args}");
+                }
+                self.add_watchpoint(args);
+                writeln!(output, "watchpoint: {args}")?;
+            },
+            "u" | "unwatch" => {
+                if !self.remove_watchpoint(args) {
+                    bail!("register not watched: {args}");
+                }
+            },
+            "i" | "info" => {
+                for (id, breakpoint) in self.breakpoints() {
+                    writeln!(output, "breakpoint {id}: {breakpoint}")?;
+                }
+                for register in self.watchpoints() {
+                    writeln!(output, "watchpoint: {register}")?;
+                }
+            },
+            "r" | "registers" => {
+                for register in self.vm.program().names().iter() {
+                    writeln!(output, "{register} = {}", self.vm.registers().get_big(register))?;
+                }
+            },
+            "x" | "context" => writeln!(output, "{}", self.vm.context())?,
+            "l" | "list" => {
+                let ip = self.vm.ip();
+                for (line_ip, line) in self.vm.program().listing().to_string().lines().enumerate() {
+                    let marker = if line_ip as i64 == ip { "=>" } else { "  " };
+                    writeln!(output, "{marker} {line}")?;
+                }
+            },
+            command => {
+                return Err(anyhow!(
+                    "unknown command: {command} (enter `help` for a list of commands)"
+                ))
+            },
+        }
+
+        Ok(())
+    }
+
+    fn write_stop<W>(&self, stop: &Stop, output: &mut W) -> Result<(), anyhow::Error>
+    where
+        W: Write,
+    {
+        match stop {
+            Stop::Step(_) => {},
+            Stop::Breakpoint(id) => {
+                writeln!(output, "hit breakpoint {id}: {}", self.breakpoints[id])?
+            },
+            Stop::Watchpoint { register, old, new } => {
+                writeln!(output, "watchpoint: {register} changed from {old} to {new}")?
+            },
+            Stop::Stalled(InstructionResult::Exited) => writeln!(output, "program exited")?,
+            Stop::Stalled(_) => writeln!(output, "program is waiting")?,
+            Stop::HistoryStart => writeln!(output, "reached the start of the recorded history")?,
+        }
+
+        Ok(())
+    }
+
+    fn write_location<W>(&self, output: &mut W) -> Result<(), anyhow::Error>
+    where
+        W: Write,
+    {
+        let ip = self.vm.ip();
+        match self.vm.program().get(ip) {
+            Some(instruction) => writeln!(output, "{ip}: {instruction}")?,
+            None => writeln!(output, "{ip}: <outside program>")?,
+        }
+
+        Ok(())
+    }
+}
+
+const HELP: &str = "\
+s, step [n]             execute one (or n) instructions
+c, continue             execute until a breakpoint or watchpoint is hit, or the program stalls
+rs, rstep [n]           undo one (or n) instructions
+rc, rcontinue           undo instructions until a breakpoint or watchpoint is hit
+last <register>         go back to right before the last change of a register
+b, break <breakpoint>   add a breakpoint: `<ip>`, `<ip> if <cond>` or `<cond>` (e.g. `a > 3`)
+d, delete <id>          delete a breakpoint
+w, watch <register>     stop whenever a register changes
+u, unwatch <register>   stop watching a register
+i, info                 list breakpoints and watchpoints
+r, registers            show registers
+x, context              show machine state (queues, counters, etc.)
+l, list                 show program listing
+q, quit                 end the debugging session";
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+    use crate::helpers::duet::tests::TestInstruction;
+
+    fn debugger() -> Debugger<TestInstruction> {
+        let program = "add a 3\nadd b 2\nadd a -1\njnz a -2".parse().unwrap();
+        Debugger::new(Vm::new(program, 0))
+    }
+
+    #[test]
+    fn test_breakpoint_parsing() {
+        assert_eq!(Breakpoint::at(12), "12".parse().unwrap());
+        assert_eq!(
+            Breakpoint {
+                ip: Some(3),
+                condition: Some(Condition {
+                    register: "a".into(),
+                    comparison: Comparison::Le,
+                    value: -1
+                }),
+            },
+            "3 if a <= -1".parse().unwrap()
+        );
+        assert_eq!("b != 2", "b != 2".parse::<Breakpoint>().unwrap().to_string());
+        assert!("a =< 2".parse::<Breakpoint>().is_err());
+        assert!("x if a == 2".parse::<Breakpoint>().is_err());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger();
+        let id = debugger.add_breakpoint(Breakpoint::at(1));
+
+        assert_eq!(Stop::Breakpoint(id), debugger.resume().unwrap());
+        assert_eq!((1, 3), (debugger.vm().ip(), debugger.vm().register("a")));
+        assert_eq!(Stop::Breakpoint(id), debugger.resume().unwrap());
+        assert_eq!((1, 2), (debugger.vm().ip(), debugger.vm().register("a")));
+
+        debugger.remove_breakpoint(id);
+        let id = debugger.add_breakpoint("b == 6".parse().unwrap());
+        assert_eq!(Stop::Breakpoint(id), debugger.resume().unwrap());
+        assert_eq!((2, 1), (debugger.vm().ip(), debugger.vm().register("a")));
+
+        debugger.remove_breakpoint(id);
+        assert_eq!(Stop::Stalled(InstructionResult::Exited), debugger.resume().unwrap());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debugger();
+        debugger.add_watchpoint("b");
+
+        assert_eq!(Stop::Step(InstructionResult::Unit), debugger.step().unwrap());
+        assert_eq!(
+            Stop::Watchpoint { register: "b".into(), old: 0, new: 2 },
+            debugger.resume().unwrap()
+        );
+        assert_eq!(
+            Stop::Watchpoint { register: "b".into(), old: 2, new: 4 },
+            debugger.resume().unwrap()
+        );
+        assert_eq!(5, *debugger.vm().context());
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mut debugger = debugger();
+        debugger.vm_mut().record_history(None);
+        assert_eq!(Stop::Stalled(InstructionResult::Exited), debugger.resume().unwrap());
+
+        debugger.add_watchpoint("b");
+        assert_eq!(Stop::Step(InstructionResult::Unit), debugger.step_back().unwrap());
+        assert_eq!(
+            Stop::Watchpoint { register: "b".into(), old: 4, new: 6 },
+            debugger.reverse().unwrap()
+        );
+        assert_eq!((1, 7), (debugger.vm().ip(), debugger.vm().steps()));
+
+        debugger.remove_watchpoint("b");
+        let id = debugger.add_breakpoint("a == 3".parse().unwrap());
+        assert_eq!(Stop::Breakpoint(id), debugger.reverse().unwrap());
+        assert_eq!((2, 2), (debugger.vm().ip(), debugger.vm().steps()));
+        debugger.remove_breakpoint(id);
+        assert_eq!(Stop::HistoryStart, debugger.reverse().unwrap());
+        assert_eq!(Stop::HistoryStart, debugger.step_back().unwrap());
+
+        // Executing forward again gives the same results.
+        assert_eq!(Stop::Stalled(InstructionResult::Exited), debugger.resume().unwrap());
+        assert_eq!((0, 6), (debugger.vm().register("a"), debugger.vm().register("b")));
+        assert_eq!(10, *debugger.vm().context());
+    }
+
+    #[test]
+    fn test_last_change() {
+        let mut debugger = debugger();
+        debugger.vm_mut().record_history(None);
+        debugger.resume().unwrap();
+
+        assert_eq!((1, 0), debugger.last_change("a").unwrap());
+        assert_eq!((2, 8), (debugger.vm().ip(), debugger.vm().steps()));
+        assert_eq!((4, 6), debugger.last_change("b").unwrap());
+        assert_eq!((1, 7), (debugger.vm().ip(), debugger.vm().steps()));
+        assert_eq!("unknown register: x", debugger.last_change("x").unwrap_err().to_string());
+
+        // `a` never changes: the program is executed again up to where it was.
+        let program = "add a 0\nadd b 1\nadd b 1".parse().unwrap();
+        let mut debugger: Debugger<TestInstruction> = Debugger::new(Vm::new(program, 0));
+        debugger.vm_mut().record_history(None);
+        debugger.resume().unwrap();
+        let err = debugger.last_change("a").unwrap_err();
+        assert_eq!("register a did not change in the recorded history", err.to_string());
+        assert_eq!(
+            (3, 3, 2),
+            (debugger.vm().ip(), debugger.vm().steps(), debugger.vm().register("b"))
+        );
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut debugger = debugger();
+        let mut output = Vec::new();
+        debugger
+            .repl("b 3\nc\nr\nx\nq\ns\n".as_bytes(), &mut output)
+            .unwrap();
+
+        let expected = "0: add a 3\n\
+                        (duet) breakpoint 1: 3\n\
+                        (duet) hit breakpoint 1: 3\n\
+                        3: jnz a -2\n\
+                        (duet) a = 2\n\
+                        b = 2\n\
+                        (duet) 3\n\
+                        (duet) ";
+        assert_eq!(expected, String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn test_repl_reverse() {
+        let mut debugger = debugger();
+        debugger.vm_mut().record_history(None);
+        let mut output = Vec::new();
+        debugger
+            .repl("s 5\nlast b\nrs 2\nrc\n".as_bytes(), &mut output)
+            .unwrap();
+
+        let expected = "0: add a 3\n\
+                        (duet) 2: add a -1\n\
+                        (duet) b changed from 2 to 4 at step 4\n\
+                        1: add b 2\n\
+                        (duet) 2: add a -1\n\
+                        (duet) reached the start of the recorded history\n\
+                        0: add a 3\n\
+                        (duet) ";
+        assert_eq!(expected, String::from_utf8(output).unwrap());
+    }
+}
@@ -0,0 +1,1063 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Range;
+
+use itertools::Itertools;
+
+use crate::helpers::duet::{InstructionSet, JumpCondition, Operation, Program, Register, Value};
+
+/// [`InstructionSet`] whose programs can be decompiled to structured pseudocode; see
+/// [`Program::decompile`].
+pub trait Decompile: InstructionSet {
+    /// Returns the statement performed by the instruction, or `None` for jumps, which are
+    /// decompiled to control flow.
+    fn statement(&self) -> Option<Statement>;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+
+    /// Euclidean remainder, like [`Operation::Mod`].
+    Mod,
+
+    Eq,
+    Ne,
+    Gt,
+    Le,
+}
+
+impl BinaryOp {
+    fn precedence(self) -> u8 {
+        match self {
+            Self::Eq | Self::Ne | Self::Gt | Self::Le => 0,
+            Self::Add | Self::Sub => 1,
+            Self::Mul | Self::Mod => 2,
+        }
+    }
+
+    fn is_comparison(self) -> bool {
+        self.precedence() == 0
+    }
+
+    /// Returns the comparison that holds exactly when this one doesn't.
+    fn negated(self) -> Option<Self> {
+        match self {
+            Self::Eq => Some(Self::Ne),
+            Self::Ne => Some(Self::Eq),
+            Self::Gt => Some(Self::Le),
+            Self::Le => Some(Self::Gt),
+            _ => None,
+        }
+    }
+
+    /// Evaluates the operation, or returns `None` if it would overflow or divide by zero.
+    fn fold(self, a: i64, b: i64) -> Option<i64> {
+        match self {
+            Self::Add => a.checked_add(b),
+            Self::Sub => a.checked_sub(b),
+            Self::Mul => a.checked_mul(b),
+            Self::Mod => a.checked_rem_euclid(b),
+            Self::Eq => Some(i64::from(a == b)),
+            Self::Ne => Some(i64::from(a != b)),
+            Self::Gt => Some(i64::from(a > b)),
+            Self::Le => Some(i64::from(a <= b)),
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Add => write!(f, "+"),
+            Self::Sub => write!(f, "-"),
+            Self::Mul => write!(f, "*"),
+            Self::Mod => write!(f, "mod"),
+            Self::Eq => write!(f, "=="),
+            Self::Ne => write!(f, "!="),
+            Self::Gt => write!(f, ">"),
+            Self::Le => write!(f, "<="),
+        }
+    }
+}
+
+/// Expression of the pseudocode. Expressions are pure: evaluating them has no side effects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+
+    /// Binary operation, built with [`Expr::binary`] to keep it simplified.
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+
+    /// Call to a built-in function, like `is_prime(b)`.
+    Call(&'static str, Vec<Expr>),
+
+    Not(Box<Expr>),
+}
+
+impl Expr {
+    /// Returns `left op right`, simplified: constants are folded, additions of constants
+    /// are merged, and comparisons of differences with `0` become direct comparisons
+    /// (`a - b != 0` is `a != b`).
+    pub fn binary(left: Self, op: BinaryOp, right: Self) -> Self {
+        use BinaryOp::{Add, Mul, Sub};
+
+        if let (Self::Number(a), Self::Number(b)) = (&left, &right) {
+            if let Some(n) = op.fold(*a, *b) {
+                return Self::Number(n);
+            }
+        }
+
+        if let Self::Number(b) = right {
+            if let Some(b) = match op {
+                Add => Some(b),
+                Sub => b.checked_neg(),
+                _ => None,
+            } {
+                // (x + a) + b is x + (a + b).
+                if let Some((x, a)) = left.offset() {
+                    if let Some(n) = a.checked_add(b) {
+                        return Self::binary(x.clone(), Add, Self::Number(n));
+                    }
+                }
+                // x + -n is x - n, and x - -n is x + n.
+                match b {
+                    0 => return left,
+                    b if b < 0 && b != i64::MIN => {
+                        return Self::Binary(Box::new(left), Sub, Box::new(Self::Number(-b)))
+                    },
+                    b => return Self::Binary(Box::new(left), Add, Box::new(Self::Number(b))),
+                }
+            }
+        }
+
+        if op.is_comparison() && right == Self::Number(0) {
+            if let Self::Binary(x, Sub, y) = left {
+                return Self::binary(*x, op, *y);
+            }
+            if let Some((x, n)) = left.offset() {
+                if let Some(n) = n.checked_neg() {
+                    return Self::binary(x.clone(), op, Self::Number(n));
+                }
+            }
+        }
+
+        match (left, op, right) {
+            // -x + y is y - x.
+            (Self::Binary(x, Mul, minus_one), Add, y) if *minus_one == Self::Number(-1) => {
+                Self::binary(y, Sub, *x)
+            },
+            (Self::Number(0), Add, other) | (Self::Number(1), Mul, other) => other,
+            (other, Mul, Self::Number(1)) => other,
+            (left, op, right) => Self::Binary(Box::new(left), op, Box::new(right)),
+        }
+    }
+
+    /// Returns a call to the built-in function `name`.
+    pub fn call(name: &'static str, args: Vec<Self>) -> Self {
+        Self::Call(name, args)
+    }
+
+    /// Returns `x` and `n` if the expression is `x + n` or `x - n`, for a constant `n`.
+    fn offset(&self) -> Option<(&Self, i64)> {
+        match self {
+            Self::Binary(x, BinaryOp::Add, n) => match **n {
+                Self::Number(n) => Some((x, n)),
+                _ => None,
+            },
+            Self::Binary(x, BinaryOp::Sub, n) => match **n {
+                Self::Number(n) => Some((x, n.checked_neg()?)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the condition that holds exactly when this one doesn't.
+    pub fn negate(self) -> Self {
+        match self {
+            Self::Binary(left, op, right) if op.is_comparison() => {
+                Self::Binary(left, op.negated().expect("comparisons can be negated"), right)
+            },
+            Self::Not(expr) => *expr,
+            expr => Self::Not(Box::new(expr)),
+        }
+    }
+
+    /// Replaces every read of `register` with `value`.
+    fn substitute(&self, register: &Register, value: &Self) -> Self {
+        match self {
+            Self::Register(r) if r == register => value.clone(),
+            Self::Binary(left, op, right) => Self::binary(
+                left.substitute(register, value),
+                *op,
+                right.substitute(register, value),
+            ),
+            Self::Call(name, args) => Self::Call(
+                name,
+                args.iter()
+                    .map(|arg| arg.substitute(register, value))
+                    .collect(),
+            ),
+            Self::Not(expr) => Self::Not(Box::new(expr.substitute(register, value))),
+            expr => expr.clone(),
+        }
+    }
+
+    /// Returns how many times the expression reads `register`.
+    fn reads(&self, register: &Register) -> usize {
+        match self {
+            Self::Number(_) => 0,
+            Self::Register(r) => usize::from(r == register),
+            Self::Binary(left, _, right) => left.reads(register) + right.reads(register),
+            Self::Call(_, args) => args.iter().map(|arg| arg.reads(register)).sum(),
+            Self::Not(expr) => expr.reads(register),
+        }
+    }
+
+    /// Adds the indices of the registers read by the expression to `registers`.
+    fn registers(&self, registers: &mut BTreeSet<usize>) {
+        match self {
+            Self::Number(_) => {},
+            Self::Register(r) => {
+                registers.insert(r.index());
+            },
+            Self::Binary(left, _, right) => {
+                left.registers(registers);
+                right.registers(registers);
+            },
+            Self::Call(_, args) => args.iter().for_each(|arg| arg.registers(registers)),
+            Self::Not(expr) => expr.registers(registers),
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Binary(_, op, _) => op.precedence(),
+            _ => u8::MAX,
+        }
+    }
+}
+
+impl From<&Value> for Expr {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Number(n) => Self::Number(*n),
+            Value::Register(register) => Self::Register(register.clone()),
+        }
+    }
+}
+
+/// Operators are left-associative, so right operands of the same precedence are parenthesized.
+/// Multiplications and remainders are also parenthesized when mixed, for readability.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{n}"),
+            Self::Register(register) => write!(f, "{register}"),
+            Self::Binary(left, op, right) => {
+                let needs_parentheses = |operand: &Self, is_right: bool| match operand {
+                    Self::Binary(_, inner, _) if inner.precedence() == op.precedence() => {
+                        is_right || (inner != op && matches!(op, BinaryOp::Mul | BinaryOp::Mod))
+                    },
+                    operand => operand.precedence() < op.precedence(),
+                };
+                if needs_parentheses(left, false) {
+                    write!(f, "({left})")?;
+                } else {
+                    write!(f, "{left}")?;
+                }
+                write!(f, " {op} ")?;
+                if needs_parentheses(right, true) {
+                    write!(f, "({right})")
+                } else {
+                    write!(f, "{right}")
+                }
+            },
+            Self::Call(name, args) => write!(f, "{name}({})", args.iter().join(", ")),
+            Self::Not(expr) if expr.precedence() < u8::MAX => write!(f, "!({expr})"),
+            Self::Not(expr) => write!(f, "!{expr}"),
+        }
+    }
+}
+
+/// Statement of the pseudocode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    /// Shown as a compound assignment (`a += 1`) when possible.
+    Assign(Register, Expr),
+
+    /// Call to a built-in procedure, like `send(a)`.
+    Call(&'static str, Vec<Expr>),
+
+    If(Expr, Vec<Statement>, Vec<Statement>),
+    While(Expr, Vec<Statement>),
+    DoWhile(Vec<Statement>, Expr),
+
+    /// Loop only left with `break`, `exit` or `goto`.
+    Loop(Vec<Statement>),
+
+    /// Leaves the innermost loop.
+    Break,
+
+    /// Goes to the next iteration of the innermost loop, evaluating its condition first.
+    Continue,
+
+    /// Exits the program.
+    Exit,
+
+    /// Target of [`Statement::Goto`], for the instruction at the given instruction pointer.
+    Label(usize),
+
+    /// Jump to the instruction at the given instruction pointer, when it can't be expressed
+    /// with structured control flow.
+    Goto(usize),
+
+    /// Jump to the instruction pointer computed by the expression, for jumps whose offset
+    /// is stored in a register.
+    Jump(Expr),
+}
+
+impl Statement {
+    /// Returns the statement applying `operation` to `register` and `value`;
+    /// see [`Registers::apply`](crate::helpers::duet::Registers::apply).
+    pub fn apply(register: &Register, operation: Operation, value: &Value) -> Self {
+        let op = match operation {
+            Operation::Set => return Self::Assign(register.clone(), value.into()),
+            Operation::Add => BinaryOp::Add,
+            Operation::Sub => BinaryOp::Sub,
+            Operation::Mul => BinaryOp::Mul,
+            Operation::Mod => BinaryOp::Mod,
+        };
+        Self::Assign(
+            register.clone(),
+            Expr::binary(Expr::Register(register.clone()), op, value.into()),
+        )
+    }
+
+    /// Adds the indices of the registers the statement may read to `registers`.
+    fn reads(&self, registers: &mut BTreeSet<usize>) {
+        let (exprs, blocks): (Vec<&Expr>, Vec<&[Statement]>) = match self {
+            Self::Assign(_, expr) | Self::Jump(expr) => (vec![expr], vec![]),
+            Self::Call(_, args) => (args.iter().collect(), vec![]),
+            Self::If(condition, then, otherwise) => (vec![condition], vec![then, otherwise]),
+            Self::While(condition, body) | Self::DoWhile(body, condition) => {
+                (vec![condition], vec![body])
+            },
+            Self::Loop(body) => (vec![], vec![body]),
+            Self::Break | Self::Continue | Self::Exit | Self::Label(_) | Self::Goto(_) => {
+                (vec![], vec![])
+            },
+        };
+        exprs.into_iter().for_each(|expr| expr.registers(registers));
+        for statement in blocks.into_iter().flatten() {
+            statement.reads(registers);
+        }
+    }
+}
+
+/// Structured pseudocode of a [`Program`]. See [`Program::decompile`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pseudocode {
+    statements: Vec<Statement>,
+    labels: BTreeMap<i64, String>,
+}
+
+impl Pseudocode {
+    pub fn statements(&self) -> &[Statement] {
+        &self.statements
+    }
+
+    fn write_block(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        statements: &[Statement],
+        depth: usize,
+    ) -> fmt::Result {
+        let indent = "    ".repeat(depth);
+        for statement in statements {
+            match statement {
+                Statement::Assign(register, Expr::Binary(left, op, right))
+                    if matches!(op, BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul)
+                        && **left == Expr::Register(register.clone()) =>
+                {
+                    writeln!(f, "{indent}{register} {op}= {right}")?
+                },
+                Statement::Assign(register, expr) => writeln!(f, "{indent}{register} = {expr}")?,
+                Statement::Call(name, args) => {
+                    writeln!(f, "{indent}{name}({})", args.iter().join(", "))?
+                },
+                Statement::If(condition, then, otherwise) => {
+                    writeln!(f, "{indent}if {condition} {{")?;
+                    self.write_block(f, then, depth + 1)?;
+                    if !otherwise.is_empty() {
+                        writeln!(f, "{indent}}} else {{")?;
+                        self.write_block(f, otherwise, depth + 1)?;
+                    }
+                    writeln!(f, "{indent}}}")?;
+                },
+                Statement::While(condition, body) => {
+                    writeln!(f, "{indent}while {condition} {{")?;
+                    self.write_block(f, body, depth + 1)?;
+                    writeln!(f, "{indent}}}")?;
+                },
+                Statement::DoWhile(body, condition) => {
+                    writeln!(f, "{indent}do {{")?;
+                    self.write_block(f, body, depth + 1)?;
+                    writeln!(f, "{indent}}} while {condition}")?;
+                },
+                Statement::Loop(body) => {
+                    writeln!(f, "{indent}loop {{")?;
+                    self.write_block(f, body, depth + 1)?;
+                    writeln!(f, "{indent}}}")?;
+                },
+                Statement::Break => writeln!(f, "{indent}break")?,
+                Statement::Continue => writeln!(f, "{indent}continue")?,
+                Statement::Exit => writeln!(f, "{indent}exit")?,
+                Statement::Label(ip) => writeln!(f, "{indent}{}:", self.label(*ip))?,
+                Statement::Goto(ip) => writeln!(f, "{indent}goto {}", self.label(*ip))?,
+                Statement::Jump(target) => writeln!(f, "{indent}jump {target}")?,
+            }
+        }
+        Ok(())
+    }
+
+    fn label(&self, ip: usize) -> &str {
+        self.labels
+            .get(&(ip as i64))
+            .expect("jump targets should have a label")
+    }
+}
+
+impl fmt::Display for Pseudocode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_block(f, &self.statements, 0)
+    }
+}
+
+impl<I> Program<I>
+where
+    I: Decompile,
+{
+    /// Decompiles the program to structured pseudocode.
+    ///
+    /// Consecutive assignments to the same register are merged into one expression, and
+    /// registers only used to compute the condition of a jump are folded into it; registers
+    /// are assumed to be unused once the program exits. Jumps become `if`, `else` and loops
+    /// where possible, falling back to `goto` for blocks that can be entered in the middle.
+    /// Jumps whose offset is stored in a register are assumed never to land inside a block.
+    pub fn decompile(&self) -> Pseudocode {
+        let mut decompiler = Decompiler::new(self);
+        decompiler.merge_assignments();
+        decompiler.inline_conditions();
+
+        let mut statements = decompiler.structure(0..self.len(), None);
+        let mut targets = BTreeSet::new();
+        goto_targets(&statements, &mut targets);
+        remove_unused_labels(&mut statements, &targets);
+        Pseudocode { statements, labels: self.labels() }
+    }
+}
+
+/// Instruction lifted to pseudocode, before control flow is structured.
+#[derive(Debug, Clone)]
+enum Lifted {
+    /// Instruction without effect, or merged into a later one.
+    Nop,
+
+    Statement(Statement),
+
+    /// Jump, taken when `condition` holds if there is one.
+    Jump {
+        condition: Option<Expr>,
+        target: Target,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Fixed(i64),
+    Computed(Expr),
+}
+
+/// Innermost loop around the instructions being structured.
+#[derive(Debug, Copy, Clone)]
+struct LoopContext {
+    header: usize,
+
+    /// Instruction pointer of the jump back to the header.
+    end: usize,
+
+    /// Whether the jump back to the header is always taken.
+    unconditional: bool,
+}
+
+struct Decompiler {
+    lifted: Vec<Lifted>,
+
+    /// Source and target of every jump with a constant offset.
+    jumps: Vec<(usize, usize)>,
+
+    /// Instruction pointers jumped to, inside the program.
+    targets: BTreeSet<usize>,
+}
+
+impl Decompiler {
+    fn new<I>(program: &Program<I>) -> Self
+    where
+        I: Decompile,
+    {
+        let mut lifted = program
+            .instructions()
+            .iter()
+            .enumerate()
+            .map(|(ip, instruction)| lift(ip, instruction))
+            .collect_vec();
+        // Jumping over instructions without effect (like `jnz 1 1`) has no effect either.
+        for ip in (0..lifted.len()).rev() {
+            if let Lifted::Jump { target: Target::Fixed(target), .. } = lifted[ip] {
+                let skipped = usize::try_from(target)
+                    .ok()
+                    .filter(|&target| target > ip)
+                    .map(|target| ip + 1..target.min(lifted.len()));
+                if skipped.is_some_and(|skipped| {
+                    lifted[skipped]
+                        .iter()
+                        .all(|lifted| matches!(lifted, Lifted::Nop))
+                }) {
+                    lifted[ip] = Lifted::Nop;
+                }
+            }
+        }
+        let jumps = lifted
+            .iter()
+            .enumerate()
+            .filter_map(|(ip, lifted)| match lifted {
+                Lifted::Jump { target: Target::Fixed(target), .. } => {
+                    let target = usize::try_from(*target).ok()?;
+                    (target < program.len()).then_some((ip, target))
+                },
+                _ => None,
+            })
+            .collect_vec();
+        let targets = jumps.iter().map(|&(_, target)| target).collect();
+        Self { lifted, jumps, targets }
+    }
+
+    /// Returns the last instruction before `ip` that isn't a [`Lifted::Nop`], if execution
+    /// always goes from it to `ip`.
+    fn previous(&self, ip: usize) -> Option<usize> {
+        let mut previous = ip;
+        loop {
+            if self.targets.contains(&previous) {
+                return None;
+            }
+            previous = previous.checked_sub(1)?;
+            if !matches!(self.lifted[previous], Lifted::Nop) {
+                return Some(previous);
+            }
+        }
+    }
+
+    /// Merges `r = x` followed by `r = f(r)` into `r = f(x)`, as long as `f` reads `r` at most
+    /// once so that expressions don't grow exponentially.
+    fn merge_assignments(&mut self) {
+        for ip in 0..self.lifted.len() {
+            let Lifted::Statement(Statement::Assign(register, expr)) = &self.lifted[ip] else {
+                continue;
+            };
+            let Some(previous) = self.previous(ip) else {
+                continue;
+            };
+            let Lifted::Statement(Statement::Assign(r, value)) = &self.lifted[previous] else {
+                continue;
+            };
+            if r == register && expr.reads(register) <= 1 {
+                let merged = Statement::Assign(register.clone(), expr.substitute(register, value));
+                self.lifted[ip] = Lifted::Statement(merged);
+                self.lifted[previous] = Lifted::Nop;
+            }
+        }
+    }
+
+    /// Folds `r = x` followed by a conditional jump on `r` into the condition, when `r` is
+    /// not read afterwards.
+    fn inline_conditions(&mut self) {
+        let live = self.live_registers();
+        for ip in 0..self.lifted.len() {
+            let Lifted::Jump { condition: Some(condition), target: Target::Fixed(_) } =
+                &self.lifted[ip]
+            else {
+                continue;
+            };
+            let Some(previous) = self.previous(ip) else {
+                continue;
+            };
+            let Lifted::Statement(Statement::Assign(register, value)) = &self.lifted[previous]
+            else {
+                continue;
+            };
+            let read_later = self
+                .successors(ip)
+                .any(|successor| live[successor].contains(&register.index()));
+            if condition.reads(register) == 1 && !read_later {
+                let condition = condition.substitute(register, value);
+                let Lifted::Jump { condition: old, .. } = &mut self.lifted[ip] else {
+                    unreachable!()
+                };
+                *old = Some(condition);
+                self.lifted[previous] = Lifted::Nop;
+            }
+        }
+    }
+
+    fn successors(&self, ip: usize) -> impl Iterator<Item = usize> {
+        let len = self.lifted.len();
+        let next = (ip + 1 < len).then_some(ip + 1);
+        let targets: Vec<usize> = match &self.lifted[ip] {
+            Lifted::Jump { condition, target: Target::Fixed(target) } => {
+                let target = usize::try_from(*target).ok().filter(|&target| target < len);
+                let next = condition.as_ref().and(next);
+                target.into_iter().chain(next).collect()
+            },
+            Lifted::Jump { target: Target::Computed(_), .. } => (0..len).collect(),
+            _ => next.into_iter().collect(),
+        };
+        targets.into_iter()
+    }
+
+    /// Returns the indices of the registers that may be read before being written again,
+    /// when reaching each instruction.
+    fn live_registers(&self) -> Vec<BTreeSet<usize>> {
+        let mut live = vec![BTreeSet::new(); self.lifted.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for ip in (0..self.lifted.len()).rev() {
+                let mut registers: BTreeSet<usize> = self
+                    .successors(ip)
+                    .flat_map(|successor| live[successor].iter().copied())
+                    .collect();
+                match &self.lifted[ip] {
+                    Lifted::Nop => {},
+                    Lifted::Statement(statement) => {
+                        if let Statement::Assign(register, _) = statement {
+                            registers.remove(&register.index());
+                        }
+                        statement.reads(&mut registers);
+                    },
+                    Lifted::Jump { condition, target } => {
+                        if let Some(condition) = condition {
+                            condition.registers(&mut registers);
+                        }
+                        if let Target::Computed(target) = target {
+                            target.registers(&mut registers);
+                        }
+                    },
+                }
+                if registers != live[ip] {
+                    live[ip] = registers;
+                    changed = true;
+                }
+            }
+        }
+        live
+    }
+
+    /// Whether a jump from outside `sources` lands in `targets`.
+    fn is_entered(&self, targets: Range<usize>, sources: Range<usize>) -> bool {
+        self.jumps
+            .iter()
+            .any(|(source, target)| !sources.contains(source) && targets.contains(target))
+    }
+
+    /// Structures the instructions in `range` into statements.
+    fn structure(&self, range: Range<usize>, context: Option<LoopContext>) -> Vec<Statement> {
+        let mut statements = Vec::new();
+        let mut ip = range.start;
+        while ip < range.end {
+            // The label of a loop's header is emitted before the loop.
+            let is_header = ip == range.start && context.is_some_and(|c| c.header == ip);
+            if self.targets.contains(&ip) && !is_header {
+                statements.push(Statement::Label(ip));
+            }
+
+            if let Some(end) = self.loop_end(ip, range.end) {
+                statements.push(self.structure_loop(ip, end));
+                ip = end + 1;
+                continue;
+            }
+
+            match &self.lifted[ip] {
+                Lifted::Nop => ip += 1,
+                Lifted::Statement(statement) => {
+                    statements.push(statement.clone());
+                    ip += 1;
+                },
+                Lifted::Jump { condition, target } => {
+                    let (statement, next) =
+                        self.structure_jump(ip, condition.clone(), target, range.end, context);
+                    statements.push(statement);
+                    ip = next;
+                },
+            }
+        }
+        statements
+    }
+
+    /// Returns the instruction pointer of the last jump back to `header` before `end`
+    /// closing a loop that can only be entered through its header, if any.
+    fn loop_end(&self, header: usize, end: usize) -> Option<usize> {
+        self.jumps
+            .iter()
+            .filter(|&&(source, target)| target == header && (header..end).contains(&source))
+            .map(|&(source, _)| source)
+            .sorted()
+            .rev()
+            .find(|&last| !self.is_entered(header + 1..last + 1, header..last + 1))
+    }
+
+    fn structure_loop(&self, header: usize, end: usize) -> Statement {
+        let Lifted::Jump { condition, .. } = &self.lifted[end] else {
+            unreachable!("loops should end with a jump");
+        };
+        let context = LoopContext { header, end, unconditional: condition.is_none() };
+        let mut body = self.structure(header..end, Some(context));
+        if self.targets.contains(&end) {
+            body.push(Statement::Label(end));
+        }
+
+        match condition {
+            Some(condition) => Statement::DoWhile(body, condition.clone()),
+            None => match body.first() {
+                Some(Statement::If(condition, then, otherwise))
+                    if *then == [Statement::Break] && otherwise.is_empty() =>
+                {
+                    let condition = condition.clone().negate();
+                    Statement::While(condition, body.split_off(1))
+                },
+                _ => Statement::Loop(body),
+            },
+        }
+    }
+
+    /// Structures the jump at `ip`, returning its statement and the instruction pointer
+    /// following it.
+    fn structure_jump(
+        &self,
+        ip: usize,
+        condition: Option<Expr>,
+        target: &Target,
+        end: usize,
+        context: Option<LoopContext>,
+    ) -> (Statement, usize) {
+        let len = self.lifted.len();
+        let statement = match target {
+            Target::Computed(target) => Statement::Jump(target.clone()),
+            &Target::Fixed(target) => {
+                if let Some(condition) = &condition {
+                    let forward = usize::try_from(target)
+                        .ok()
+                        .filter(|&target| target > ip && target <= end);
+                    if let Some(forward) = forward {
+                        if let Some(structured) =
+                            self.structure_if(ip, condition, forward, end, context)
+                        {
+                            return structured;
+                        }
+                    }
+                }
+
+                match (context, usize::try_from(target)) {
+                    (Some(c), Ok(target)) if target == c.end + 1 => Statement::Break,
+                    (Some(c), Ok(target))
+                        if target == c.end || (c.unconditional && target == c.header) =>
+                    {
+                        Statement::Continue
+                    },
+                    (_, Ok(target)) if target < len => Statement::Goto(target),
+                    _ => Statement::Exit,
+                }
+            },
+        };
+
+        let statement = match condition {
+            Some(condition) => Statement::If(condition, vec![statement], Vec::new()),
+            None => statement,
+        };
+        (statement, ip + 1)
+    }
+
+    /// Structures the conditional jump at `ip` over the instructions up to `target`
+    /// as an `if`, with an `else` if the skipped instructions end by jumping over the
+    /// following ones.
+    fn structure_if(
+        &self,
+        ip: usize,
+        condition: &Expr,
+        target: usize,
+        end: usize,
+        context: Option<LoopContext>,
+    ) -> Option<(Statement, usize)> {
+        if self.is_entered(ip + 1..target, ip + 1..target) {
+            return None;
+        }
+        let condition = condition.clone().negate();
+
+        let jump_over = match &self.lifted[target - 1] {
+            Lifted::Jump { condition: None, target: Target::Fixed(over) } if target - 1 > ip => {
+                usize::try_from(*over)
+                    .ok()
+                    .filter(|&over| over > target && over <= end)
+            },
+            _ => None,
+        };
+        if let Some(over) = jump_over {
+            let otherwise = target..over;
+            let entered = self.jumps.iter().any(|&(source, to)| {
+                otherwise.contains(&to) && !otherwise.contains(&source) && source != ip
+            });
+            if !entered {
+                let mut then = self.structure(ip + 1..target - 1, context);
+                if self.targets.contains(&(target - 1)) {
+                    then.push(Statement::Label(target - 1));
+                }
+                let otherwise = self.structure(otherwise, context);
+                let statement = if then.is_empty() {
+                    Statement::If(condition.negate(), otherwise, then)
+                } else {
+                    Statement::If(condition, then, otherwise)
+                };
+                return Some((statement, over));
+            }
+        }
+
+        let then = self.structure(ip + 1..target, context);
+        Some((Statement::If(condition, then, Vec::new()), target))
+    }
+}
+
+fn lift<I>(ip: usize, instruction: &I) -> Lifted
+where
+    I: Decompile,
+{
+    let Some(jump) = instruction.jump() else {
+        return instruction
+            .statement()
+            .map_or(Lifted::Nop, Lifted::Statement);
+    };
+
+    let target = match jump.target(ip as i64) {
+        Some(target) => Target::Fixed(target),
+        None => Target::Computed(Expr::binary(
+            Expr::Number(ip as i64),
+            BinaryOp::Add,
+            jump.offset.into(),
+        )),
+    };
+    let condition = match jump.is_taken() {
+        Some(false) => return Lifted::Nop,
+        Some(true) => None,
+        None => {
+            let op = match jump.condition {
+                JumpCondition::NonZero => BinaryOp::Ne,
+                JumpCondition::Positive => BinaryOp::Gt,
+            };
+            Some(Expr::binary(jump.value.into(), op, Expr::Number(0)))
+        },
+    };
+    Lifted::Jump { condition, target }
+}
+
+fn goto_targets(statements: &[Statement], targets: &mut BTreeSet<usize>) {
+    for statement in statements {
+        match statement {
+            Statement::Goto(target) => {
+                targets.insert(*target);
+            },
+            Statement::If(_, then, otherwise) => {
+                goto_targets(then, targets);
+                goto_targets(otherwise, targets);
+            },
+            Statement::While(_, body) | Statement::DoWhile(body, _) | Statement::Loop(body) => {
+                goto_targets(body, targets)
+            },
+            _ => {},
+        }
+    }
+}
+
+fn remove_unused_labels(statements: &mut Vec<Statement>, targets: &BTreeSet<usize>) {
+    statements
+        .retain(|statement| !matches!(statement, Statement::Label(ip) if !targets.contains(ip)));
+    for statement in statements {
+        match statement {
+            Statement::If(_, then, otherwise) => {
+                remove_unused_labels(then, targets);
+                remove_unused_labels(otherwise, targets);
+            },
+            Statement::While(_, body) | Statement::DoWhile(body, _) | Statement::Loop(body) => {
+                remove_unused_labels(body, targets)
+            },
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+    use crate::helpers::duet::tests::TestInstruction;
+    use crate::input::day_23::INPUT;
+    use crate::{day_18, day_23};
+
+    impl Decompile for TestInstruction {
+        fn statement(&self) -> Option<Statement> {
+            match self {
+                Self::Add(register, value) => {
+                    Some(Statement::apply(register, Operation::Add, value))
+                },
+                Self::Jnz(..) => None,
+            }
+        }
+    }
+
+    fn decompile(program: &str) -> String {
+        let program: Program<TestInstruction> = program.parse().unwrap();
+        program.decompile().to_string()
+    }
+
+    #[test]
+    fn test_structure() {
+        assert_eq!(
+            decompile("jnz a 3\nadd b 1\njnz 1 2\nadd c 1\nadd d 1"),
+            "if a == 0 {\n    b += 1\n} else {\n    c += 1\n}\nd += 1\n"
+        );
+        assert_eq!(
+            decompile("jnz a 2\njnz 1 4\nadd a -1\nadd b 2\njnz 1 -4"),
+            "while a != 0 {\n    a -= 1\n    b += 2\n}\n"
+        );
+        assert_eq!(
+            decompile("add a 3\nadd b 2\njnz c 2\njnz 1 10\nadd a -1\njnz a -4"),
+            "a += 3\n\
+             do {\n    b += 2\n    if c == 0 {\n        exit\n    }\n    a -= 1\n} while a != 0\n"
+        );
+    }
+
+    #[test]
+    fn test_gotos() {
+        // The loop can be entered in the middle.
+        assert_eq!(
+            decompile("jnz a 2\nadd b 1\nadd c 1\njnz c -2"),
+            "if a != 0 {\n    goto l2\n}\nl1:\nb += 1\nl2:\nc += 1\nif c != 0 {\n    goto l1\n}\n"
+        );
+        assert_eq!(decompile("add a 1\njnz a b"), "a += 1\nif a != 0 {\n    jump 1 + b\n}\n");
+    }
+
+    #[test]
+    fn test_expressions() {
+        // Consecutive additions are merged, and `g` is only used by the condition.
+        assert_eq!(
+            decompile("add a 2\nadd a -7\nadd g a\nadd g 3\njnz g 2\nadd b 1"),
+            "a -= 5\nif g + a == -3 {\n    b += 1\n}\n"
+        );
+        // `g` is read again after the jump.
+        assert_eq!(decompile("add g a\njnz g 2\nadd b g"), "g += a\nif g == 0 {\n    b += g\n}\n");
+
+        // `-a + b` is `b - a`, and mixed multiplications and remainders are parenthesized.
+        let program: Program<day_18::Instruction> =
+            "set p a\nmul p -1\nadd p b\njgz p 2\nsnd a\nset q r\nmul q 8\nmod q 5\nmul q 3\nmod q 2"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            program.decompile().to_string(),
+            "if b <= a {\n    send(a)\n}\nq = (((r * 8) mod 5) * 3) mod 2\n"
+        );
+    }
+
+    #[test]
+    fn test_non_standard_instructions() {
+        let optimized = Program::<day_23::Instruction>::default()
+            .optimize()
+            .unwrap();
+        assert!(optimized.decompile().to_string().ends_with(
+            "loop {
+    if is_prime(b) == 0 {
+        h += 1
+    }
+    if b == c {
+        break
+    }
+    b += 17
+}
+"
+        ));
+
+        // The original loop is kept as a fallback, for when the guard doesn't hold.
+        let replaced = Program::<day_23::Instruction>::default().replace_idioms();
+        assert!(replaced.decompile().to_string().contains(
+            "        g = e - b
+        if g <= 0 {
+            if g != 0 {
+                goto l25
+            }
+        }
+        do {
+            if d * e == b {
+                f = 0
+            }
+            e += 1
+        } while e != b
+        goto l28
+        l25:
+        if has_divisor_product(d, e, b) {
+            f = 0
+        }
+        e = b
+        g = 0
+        l28:
+        d += 1
+"
+        ));
+    }
+
+    #[test]
+    fn test_day_23() {
+        let program: Program<day_23::Instruction> = INPUT.parse().unwrap();
+        assert_eq!(
+            program.decompile().to_string(),
+            "b = 81
+c = b
+if a != 0 {
+    b = b * 100 + 100000
+    c = b + 17000
+}
+loop {
+    f = 1
+    d = 2
+    do {
+        e = 2
+        do {
+            if d * e == b {
+                f = 0
+            }
+            e += 1
+        } while e != b
+        d += 1
+    } while d != b
+    if f == 0 {
+        h += 1
+    }
+    if b == c {
+        break
+    }
+    b += 17
+}
+"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use anyhow::{bail, ensure, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::duet::network::{Ports, PortsSnapshot};
+use crate::helpers::duet::{InstructionResult, Queue, Register, Registers};
+
+/// Device that `snd` and `rcv` instructions communicate with, like a sound card or the
+/// [`Ports`] connecting a program to a [`Network`](crate::helpers::duet::network::Network).
+pub trait Device: fmt::Debug + fmt::Display {
+    /// Sends `value`, returning [`InstructionResult::Waiting`] if it can't be sent yet.
+    fn send(&mut self, value: i64) -> Result<InstructionResult, anyhow::Error>;
+
+    /// Receives a value for `register`, returning [`InstructionResult::Waiting`] if none
+    /// is available yet.
+    fn receive(
+        &mut self,
+        registers: &mut Registers,
+        register: &Register,
+    ) -> Result<InstructionResult, anyhow::Error>;
+
+    /// Feeds the parts of the device's state that influence execution to `state`; see
+    /// [`InstructionSet::hash_context`](crate::helpers::duet::InstructionSet::hash_context).
+    fn hash_state(&self, _state: &mut dyn Hasher) {}
+
+    /// Returns the device's state, to be saved in a
+    /// [`Snapshot`](crate::helpers::duet::snapshot::Snapshot). Stateless devices can keep the
+    /// default implementation.
+    fn snapshot(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    /// Replaces the device's state with the state returned by [`Device::snapshot`].
+    fn restore(&mut self, snapshot: serde_json::Value) -> Result<(), anyhow::Error> {
+        ensure!(snapshot.is_null(), "unexpected device state in snapshot");
+        Ok(())
+    }
+
+    /// Undoes the last `send` or `receive`, which returned `result`; see
+    /// [`Reversible`](crate::helpers::duet::history::Reversible).
+    fn undo(&mut self, _result: &InstructionResult) -> Result<(), anyhow::Error> {
+        bail!("device cannot undo operations")
+    }
+
+    /// Returns the ports connecting the device to a network, if it is connected to one.
+    fn ports(&self) -> Option<&Ports> {
+        None
+    }
+}
+
+/// Device playing sounds with `snd` and recovering the last sound played with `rcv`,
+/// when the received register is not `0`. The register is left unchanged.
+#[derive(Debug, Default)]
+pub struct SoundCard {
+    sounds: Queue,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SoundCardSnapshot {
+    sounds: Vec<i64>,
+}
+
+impl Device for SoundCard {
+    fn send(&mut self, value: i64) -> Result<InstructionResult, anyhow::Error> {
+        self.sounds.push(value);
+        Ok(InstructionResult::Sent(value))
+    }
+
+    fn receive(
+        &mut self,
+        registers: &mut Registers,
+        register: &Register,
+    ) -> Result<InstructionResult, anyhow::Error> {
+        if registers.get(register) == 0 {
+            return Ok(InstructionResult::Unit);
+        }
+        let sound = self.sounds.pop_last().context("no sound played")?;
+        Ok(InstructionResult::Received(sound))
+    }
+
+    fn undo(&mut self, result: &InstructionResult) -> Result<(), anyhow::Error> {
+        match *result {
+            InstructionResult::Sent(_) => {
+                self.sounds.pop_last();
+            },
+            InstructionResult::Received(sound) => self.sounds.push(sound),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Only the last sound played can be recovered.
+    fn hash_state(&self, mut state: &mut dyn Hasher) {
+        self.sounds.last().hash(&mut state);
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        let sounds = self.sounds.iter().copied().collect();
+        serde_json::to_value(SoundCardSnapshot { sounds }).expect("sounds should serialize")
+    }
+
+    fn restore(&mut self, snapshot: serde_json::Value) -> Result<(), anyhow::Error> {
+        let snapshot: SoundCardSnapshot =
+            serde_json::from_value(snapshot).context("snapshot of another device")?;
+        self.sounds.clear();
+        for sound in snapshot.sounds {
+            self.sounds.push(sound);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SoundCard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sounds played: {}", self.sounds)
+    }
+}
+
+/// Ports send values to the other programs of their network, and receive values from
+/// them in the received register, waiting while the input queue is empty.
+impl Device for Ports {
+    fn send(&mut self, value: i64) -> Result<InstructionResult, anyhow::Error> {
+        Ok(if Ports::send(self, value) {
+            InstructionResult::Sent(value)
+        } else {
+            InstructionResult::Waiting
+        })
+    }
+
+    fn receive(
+        &mut self,
+        registers: &mut Registers,
+        register: &Register,
+    ) -> Result<InstructionResult, anyhow::Error> {
+        Ok(match Ports::receive(self) {
+            Some(n) => {
+                registers.set(register, n);
+                InstructionResult::Received(n)
+            },
+            None => InstructionResult::Waiting,
+        })
+    }
+
+    fn hash_state(&self, mut state: &mut dyn Hasher) {
+        (*self.input()).hash(&mut state);
+    }
+
+    /// Sent values can only be unsent while they are still the last value of every target
+    /// queue. Whether the ports were blocked before a step is not restored.
+    fn undo(&mut self, result: &InstructionResult) -> Result<(), anyhow::Error> {
+        match *result {
+            InstructionResult::Sent(value) => self.unsend(value),
+            InstructionResult::Received(value) => {
+                self.unreceive(value);
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        serde_json::to_value(Ports::snapshot(self)).expect("ports should serialize")
+    }
+
+    fn restore(&mut self, snapshot: serde_json::Value) -> Result<(), anyhow::Error> {
+        let snapshot: PortsSnapshot =
+            serde_json::from_value(snapshot).context("snapshot of another device")?;
+        Ports::restore(self, snapshot)
+    }
+
+    fn ports(&self) -> Option<&Ports> {
+        Some(self)
+    }
+}
+
+impl fmt::Display for Ports {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent: {}, received: {}, receive queue: {}",
+            self.sent(),
+            self.received(),
+            self.input()
+        )
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::day_18::DuetInterpreter;
+
+    /// Device receiving scripted values and recording the values sent.
+    #[derive(Debug, Default)]
+    struct Scripted {
+        inputs: VecDeque<i64>,
+        outputs: Rc<RefCell<Vec<i64>>>,
+    }
+
+    impl Device for Scripted {
+        fn send(&mut self, value: i64) -> Result<InstructionResult, anyhow::Error> {
+            self.outputs.borrow_mut().push(value);
+            Ok(InstructionResult::Sent(value))
+        }
+
+        fn receive(
+            &mut self,
+            registers: &mut Registers,
+            register: &Register,
+        ) -> Result<InstructionResult, anyhow::Error> {
+            Ok(match self.inputs.pop_front() {
+                Some(n) => {
+                    registers.set(register, n);
+                    InstructionResult::Received(n)
+                },
+                None => InstructionResult::Waiting,
+            })
+        }
+    }
+
+    impl fmt::Display for Scripted {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{} inputs left", self.inputs.len())
+        }
+    }
+
+    #[test]
+    fn test_custom_device() {
+        let outputs = Rc::new(RefCell::new(Vec::new()));
+        let device = Scripted { inputs: [3, 5, 8].into(), outputs: Rc::clone(&outputs) };
+        let mut interpreter = DuetInterpreter::with_device(
+            "rcv a\nmul a 2\nsnd a\njgz 1 -3".parse().unwrap(),
+            device,
+        );
+        interpreter.compile();
+
+        assert_eq!(interpreter.execute().unwrap(), InstructionResult::Waiting);
+        assert_eq!(*outputs.borrow(), [6, 10, 16]);
+        assert_eq!(interpreter.context().to_string(), "0 inputs left");
+
+        // Stateless devices are saved as `null`.
+        let snapshot = interpreter.snapshot();
+        assert_eq!(snapshot.context, serde_json::Value::Null);
+        interpreter.restore(snapshot).unwrap();
+    }
+
+    #[test]
+    fn test_sound_card() {
+        let program = "set a 1\nsnd 4\nsnd 7\nrcv a\nrcv a\njgz 1 -2";
+        let mut interpreter = DuetInterpreter::for_part_1(program.parse().unwrap());
+        interpreter.detect_infinite_loops(true);
+        let results = (0..5)
+            .map(|_| interpreter.execute_next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results[3..], [InstructionResult::Received(7), InstructionResult::Received(4)]);
+
+        let snapshot = interpreter.snapshot();
+        assert_eq!(snapshot.context, serde_json::json!({ "sounds": [] }));
+        let err = interpreter.execute().unwrap_err();
+        assert_eq!(err.to_string(), "no sound played");
+
+        let mut networked = DuetInterpreter::networked(program.parse().unwrap(), Ports::looped());
+        let err = networked.restore(snapshot).unwrap_err();
+        assert!(
+            format!("{err:#}")
+                .starts_with("invalid context in snapshot: snapshot of another device"),
+            "{err:#}"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::VecDeque;
+
+use anyhow::Context;
+use num::BigInt;
+
+use crate::helpers::duet::{InstructionResult, InstructionSet, Registers, Vm};
+
+/// [`InstructionSet`] whose steps can be undone; see [`Vm::step_back`].
+pub trait Reversible: InstructionSet {
+    /// Undoes the changes made to `context` by executing the instruction, which returned `result`.
+    ///
+    /// Register changes are undone by the [`Vm`] itself.
+    fn undo(
+        &self,
+        context: &mut Self::Context,
+        result: &InstructionResult,
+    ) -> Result<(), anyhow::Error>;
+}
+
+/// Changes made to a [`Vm`] by a single step, enough to undo it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    /// Instruction pointer before the step.
+    pub ip: i64,
+
+    pub result: InstructionResult,
+
+    /// Index and old value of every register modified by the step, along with the old exact
+    /// value of registers that didn't fit in an `i64`.
+    registers: Vec<(usize, i64, Option<BigInt>)>,
+}
+
+impl Delta {
+    pub(super) fn new(
+        ip: i64,
+        result: InstructionResult,
+        before: &Registers,
+        after: &Registers,
+    ) -> Self {
+        let registers = after
+            .changes(before)
+            .map(|(index, old, _)| (index, old, before.big.get(&index).cloned()))
+            .collect();
+        Self { ip, result, registers }
+    }
+
+    /// Returns the indices of the registers modified by the step.
+    pub fn modified(&self) -> impl Iterator<Item = usize> + '_ {
+        self.registers.iter().map(|&(index, ..)| index)
+    }
+
+    fn undo(&self, registers: &mut Registers) {
+        for (index, old, big) in &self.registers {
+            registers.values[*index] = *old;
+            match big {
+                Some(big) => registers.big.insert(*index, big.clone()),
+                None => registers.big.remove(index),
+            };
+        }
+    }
+}
+
+/// Deltas of the last steps executed by a [`Vm`], oldest first; see [`Vm::record_history`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct History {
+    deltas: VecDeque<Delta>,
+    capacity: Option<usize>,
+}
+
+impl History {
+    /// Creates a history remembering at most `capacity` steps, forgetting the oldest ones first.
+    pub fn new(capacity: Option<usize>) -> Self {
+        Self { deltas: VecDeque::new(), capacity }
+    }
+
+    pub(super) fn push(&mut self, delta: Delta) {
+        if self.capacity == Some(self.deltas.len()) {
+            self.deltas.pop_front();
+        }
+        if self.capacity != Some(0) {
+            self.deltas.push_back(delta);
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.deltas.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Delta> {
+        self.deltas.iter()
+    }
+}
+
+impl<I> Vm<I>
+where
+    I: Reversible,
+{
+    /// Undoes the last step recorded in the machine's history, returning its delta,
+    /// or `None` if there is no step left to undo.
+    ///
+    /// Steps executed again after stepping back are recorded again. Remembered states used
+    /// to detect infinite loops are forgotten.
+    pub fn step_back(&mut self) -> Result<Option<Delta>, anyhow::Error> {
+        let Some(delta) = self
+            .history
+            .as_mut()
+            .and_then(|history| history.deltas.pop_back())
+        else {
+            return Ok(None);
+        };
+
+        let instruction = self
+            .program
+            .get(delta.ip)
+            .expect("recorded steps should be inside the program");
+        if let Err(err) = instruction.undo(&mut self.context, &delta.result) {
+            self.history
+                .as_mut()
+                .expect("history should be recorded")
+                .deltas
+                .push_back(delta);
+            return Err(err).with_context(|| format!("cannot undo step {}", self.steps - 1));
+        }
+
+        delta.undo(&mut self.registers);
+        self.ip = delta.ip;
+        self.steps -= 1;
+        if let Some(seen_states) = &mut self.seen_states {
+            seen_states.clear();
+        }
+        Ok(Some(delta))
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+    use crate::day_18::DuetInterpreter;
+    use crate::day_23::{ExperimentalCoprocessor, Instruction, InstructionDiscriminants, OpCounts};
+    use crate::helpers::duet::network::NetworkBuilder;
+    use crate::helpers::duet::tests::TestInstruction;
+    use crate::helpers::duet::{Arithmetic, Program};
+    use crate::input::day_23::INPUT;
+
+    impl Reversible for TestInstruction {
+        fn undo(&self, steps: &mut usize, _: &InstructionResult) -> Result<(), anyhow::Error> {
+            *steps -= 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_step_back() {
+        let program: Program<TestInstruction> =
+            "add a 3\nadd b 2\nadd a -1\njnz a -2".parse().unwrap();
+        let mut vm = Vm::new(program, 0);
+        vm.record_history(None);
+        vm.execute().unwrap();
+        assert_eq!(vm.history().unwrap().len(), 10);
+
+        let delta = vm.step_back().unwrap().unwrap();
+        assert_eq!((delta.ip, delta.result), (3, InstructionResult::Unit));
+        assert_eq!(delta.modified().count(), 0);
+        for _ in 0..3 {
+            vm.step_back().unwrap();
+        }
+        assert_eq!((vm.ip(), vm.steps(), *vm.context()), (3, 6, 6));
+        assert_eq!((vm.register("a"), vm.register("b")), (1, 4));
+
+        while vm.step_back().unwrap().is_some() {}
+        assert_eq!((vm.ip(), vm.steps(), *vm.context()), (0, 0, 0));
+        assert_eq!((vm.register("a"), vm.register("b")), (0, 0));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let program: Program<TestInstruction> = "add a 5\nadd a -1\njnz a -1".parse().unwrap();
+        let mut vm = Vm::new(program, 0);
+        vm.record_history(Some(4));
+        vm.execute().unwrap();
+
+        for _ in 0..4 {
+            assert!(vm.step_back().unwrap().is_some());
+        }
+        assert_eq!(vm.step_back().unwrap(), None);
+        assert_eq!((vm.ip(), vm.register("a")), (1, 2));
+    }
+
+    #[test]
+    fn test_rerun() {
+        let program: Program<Instruction> = INPUT.parse().unwrap();
+        let mut coprocessor = ExperimentalCoprocessor::new(program, OpCounts::default());
+        coprocessor.set_arithmetic(Arithmetic::BigInt);
+        coprocessor.record_history(None);
+        for _ in 0..5000 {
+            coprocessor.execute_next().unwrap();
+        }
+        let (registers, mul) =
+            (coprocessor.registers().clone(), coprocessor.op_count(InstructionDiscriminants::Mul));
+
+        for _ in 0..3000 {
+            coprocessor.step_back().unwrap();
+        }
+        assert!(coprocessor.op_count(InstructionDiscriminants::Mul) < mul);
+        for _ in 0..3000 {
+            coprocessor.execute_next().unwrap();
+        }
+        assert_eq!(coprocessor.registers(), &registers);
+        assert_eq!(coprocessor.op_count(InstructionDiscriminants::Mul), mul);
+        assert_eq!(coprocessor.history().unwrap().len(), 5000);
+    }
+
+    #[test]
+    fn test_devices() {
+        let program = "snd 1\nset a 1\nsnd 2\nrcv a\nrcv a\nsnd 3\nrcv a";
+        let mut interpreter = DuetInterpreter::for_part_1(program.parse().unwrap());
+        interpreter.record_history(None);
+        let results = (0..7)
+            .map(|_| interpreter.execute_next().unwrap())
+            .collect::<Vec<_>>();
+        let context = interpreter.context().to_string();
+
+        for _ in 0..4 {
+            interpreter.step_back().unwrap();
+        }
+        assert_eq!(interpreter.context().to_string(), "sounds played: [1, 2]");
+        for result in &results[3..] {
+            assert_eq!(&interpreter.execute_next().unwrap(), result);
+        }
+        assert_eq!(interpreter.context().to_string(), context);
+
+        // Values received by another program can't be unsent.
+        let program = "snd 7\nrcv a".parse().unwrap();
+        let mut network = NetworkBuilder::new(2)
+            .build(|ports| {
+                let mut interpreter = DuetInterpreter::networked(Program::clone(&program), ports);
+                interpreter.record_history(None);
+                interpreter
+            })
+            .unwrap();
+        network.run().unwrap();
+        let first = &mut network.programs_mut()[0];
+        assert_eq!(first.step_back().unwrap().unwrap().result, InstructionResult::Received(7));
+        assert_eq!(first.step_back().unwrap().unwrap().result, InstructionResult::Waiting);
+        let err = first.step_back().unwrap_err();
+        assert_eq!(format!("{err:#}"), "cannot undo step 0: sent value was already received");
+        assert_eq!((first.ip(), first.steps()), (1, 1));
+    }
+}
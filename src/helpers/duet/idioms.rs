@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+
+use crate::helpers::duet::{InstructionSet, Program, RegisterNames, Vm};
+
+/// [`InstructionSet`] with superinstructions that can replace common loop idioms,
+/// like multiplication by repeated addition. See [`Program::replace_idioms`].
+pub trait LoopIdioms: InstructionSet + Clone {
+    /// Returns instructions equivalent to `body` if it is a known idiom.
+    ///
+    /// `body` is a loop whose last instruction jumps back to the first one. Replacements
+    /// can have any length, but must only jump within themselves and leave through their
+    /// end. When an idiom only holds under some preconditions, the replacement should check
+    /// them and fall back to running `body` otherwise.
+    fn replace_loop(body: &[Self]) -> Option<Vec<Self>>;
+}
+
+/// Maximum number of steps a loop can run for when verifying a replacement.
+const VERIFICATION_STEP_LIMIT: u64 = 10_000;
+
+/// Number of register states a replacement is verified against.
+const SAMPLES: usize = 64;
+
+/// Minimum number of samples for which a loop must exit to verify its replacement.
+const MIN_EXITED_SAMPLES: usize = 8;
+
+impl<I> Program<I>
+where
+    I: LoopIdioms,
+    I::Context: Default,
+{
+    /// Replaces loops implementing known idioms with superinstructions.
+    ///
+    /// Only loops that can't be entered other than from their first instruction, and that
+    /// don't overlap a loop already replaced, are candidates. Each replacement is verified by
+    /// running both versions on sample register states, and is skipped if they don't exit
+    /// for the same samples or if their registers differ once they exit. The offsets of
+    /// other jumps are updated to account for the length of the replacements.
+    pub fn replace_idioms(self) -> Self {
+        let (instructions, mut names) = self.into_parts();
+
+        // Jumps with a register offset could land in the middle of any loop.
+        let Some(jumps) = instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(ip, instruction)| {
+                let jump = instruction.jump()?;
+                Some(jump.target(ip as i64).map(|target| (ip, target)))
+            })
+            .collect::<Option<Vec<_>>>()
+        else {
+            return Self::new(instructions, names);
+        };
+
+        let mut loops: Vec<(usize, usize)> = jumps
+            .iter()
+            .filter_map(|&(ip, target)| {
+                let start = usize::try_from(target).ok()?;
+                (start < ip).then_some((start, ip + 1))
+            })
+            .collect();
+        loops.sort_by_key(|&(start, end)| (end - start, start));
+
+        // Replacements by start of the replaced loop, along with its end.
+        let mut replacements = BTreeMap::new();
+        for (start, end) in loops {
+            let entered_elsewhere = jumps.iter().any(|&(ip, target)| {
+                !(start..end).contains(&ip) && target > start as i64 && target < end as i64
+            });
+            let overlaps = replacements
+                .range(..end)
+                .next_back()
+                .is_some_and(|(_, &(replaced_end, _))| replaced_end > start);
+            if entered_elsewhere || overlaps {
+                continue;
+            }
+
+            let body = &instructions[start..end];
+            let Some(replacement) = I::replace_loop(body) else {
+                continue;
+            };
+            if is_equivalent(body, &replacement, &Program::new(Vec::new(), names.clone())) {
+                replacements.insert(start, (end, replacement));
+            }
+        }
+
+        let instructions = splice(instructions, replacements, &mut names);
+        Self::new(instructions, names)
+    }
+}
+
+/// Replaces instructions with `replacements` (by start and end of the replaced
+/// instructions), updating the offsets of the other jumps, which must all be constant.
+fn splice<I>(
+    instructions: Vec<I>,
+    mut replacements: BTreeMap<usize, (usize, Vec<I>)>,
+    names: &mut RegisterNames,
+) -> Vec<I>
+where
+    I: LoopIdioms,
+{
+    if replacements.is_empty() {
+        return instructions;
+    }
+
+    // New instruction pointer of every instruction, and of the end of the program.
+    let mut new_ips = Vec::with_capacity(instructions.len() + 1);
+    // Instructions of the new program, along with their old instruction pointer if kept.
+    let mut spliced = Vec::new();
+    let mut ip = 0;
+    while ip < instructions.len() {
+        if let Some((end, replacement)) = replacements.remove(&ip) {
+            new_ips.extend((ip..end).map(|_| spliced.len()));
+            spliced.extend(
+                replacement
+                    .into_iter()
+                    .map(|instruction| (None, instruction)),
+            );
+            ip = end;
+        } else {
+            new_ips.push(spliced.len());
+            spliced.push((Some(ip), instructions[ip].clone()));
+            ip += 1;
+        }
+    }
+    new_ips.push(spliced.len());
+
+    let (old_len, new_len) = (instructions.len() as i64, spliced.len() as i64);
+    let relocate = |target: i64| match usize::try_from(target) {
+        Ok(target) if target < new_ips.len() => new_ips[target] as i64,
+        // Targets outside the program stay outside.
+        _ if target < 0 => target,
+        _ => target - old_len + new_len,
+    };
+
+    spliced
+        .into_iter()
+        .enumerate()
+        .map(|(new_ip, (old_ip, instruction))| {
+            let Some((old_ip, jump)) = old_ip.zip(instruction.jump()) else {
+                return instruction;
+            };
+            let target = jump
+                .target(old_ip as i64)
+                .expect("jump offsets should be constant");
+            let offset = relocate(target) - new_ip as i64;
+
+            // Jump offsets are the last operand of jump instructions.
+            let text = instruction.to_string();
+            let operands = text
+                .strip_suffix(&jump.offset.to_string())
+                .expect("jump offset should be the last operand");
+            I::parse(&format!("{operands}{offset}"), names).expect("relocated jump should parse")
+        })
+        .collect()
+}
+
+/// Runs `body` and `replacement` on sample register states, checking that they exit for
+/// the same samples, leaving the same registers. `empty` holds the program's register names.
+fn is_equivalent<I>(body: &[I], replacement: &[I], empty: &Program<I>) -> bool
+where
+    I: LoopIdioms,
+    I::Context: Default,
+{
+    let run = |instructions: &[I], values: &[i64]| {
+        let program = Program::new(instructions.to_vec(), empty.names().clone());
+        let mut vm = Vm::new(program, I::Context::default());
+        vm.set_step_limit(Some(VERIFICATION_STEP_LIMIT));
+        for (register, &value) in empty.names().iter().zip(values) {
+            vm.set_register(register.name(), value);
+        }
+        vm.execute()
+            .ok()
+            .filter(|_| vm.ip() == instructions.len() as i64)
+            .map(|_| vm.registers().clone())
+    };
+
+    let mut exited = 0;
+    for values in samples(empty.names().len()) {
+        let expected = run(body, &values);
+        if run(replacement, &values) != expected {
+            return false;
+        }
+        exited += usize::from(expected.is_some());
+    }
+    exited >= MIN_EXITED_SAMPLES
+}
+
+/// Generates pseudo-random register states with small values, which loops are likely
+/// to exit in a few steps for.
+fn samples(registers: usize) -> impl Iterator<Item = Vec<i64>> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % 25) as i64 - 12
+    };
+    (0..SAMPLES).map(move |_| (0..registers).map(|_| next()).collect())
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+    use crate::day_23::{ExperimentalCoprocessor, Instruction, OpCounts};
+    use crate::helpers::duet::lint::DiagnosticKind;
+    use crate::input::day_23::INPUT;
+
+    fn run(program: &Program<Instruction>, registers: &[(&str, i64)]) -> ExperimentalCoprocessor {
+        let mut coprocessor = ExperimentalCoprocessor::new(program.clone(), OpCounts::default());
+        for &(name, value) in registers {
+            coprocessor.set_register(name, value);
+        }
+        coprocessor.execute().unwrap();
+        coprocessor
+    }
+
+    #[test]
+    fn test_multiply_add() {
+        let programs = [
+            ("set n 7\nset a 3\nsub a -4\nsub n 1\njnz n -2\nset b a", "mad a 4 n"),
+            ("set n 7\nset a 3\nsub n 1\nsub a k\njnz n -2\nset b a", "mad a k n"),
+            ("set n -7\nset a 3\nsub a 2\nsub n -1\njnz n -2\nset b a", "mad a 2 n"),
+        ];
+
+        for (text, superinstruction) in programs {
+            let program: Program<Instruction> = text.parse().unwrap();
+            let optimized = program.clone().replace_idioms();
+            assert!(
+                optimized.to_string().contains(superinstruction),
+                "{superinstruction} not found in:\n{optimized}"
+            );
+
+            let (expected, actual) = (run(&program, &[("k", 5)]), run(&optimized, &[("k", 5)]));
+            assert_eq!(expected.registers(), actual.registers());
+            assert!(actual.steps() < expected.steps());
+        }
+    }
+
+    #[test]
+    fn test_divisibility_test() {
+        let program: Program<Instruction> = INPUT.parse().unwrap();
+        let optimized = program.clone().replace_idioms();
+        assert!(optimized.to_string().contains("dvt f d e b"), "dvt not found in:\n{optimized}");
+
+        let (expected, actual) = (run(&program, &[]), run(&optimized, &[]));
+        assert_eq!(expected.registers(), actual.registers());
+        assert_eq!(actual.register("h"), 1);
+        assert!(actual.steps() * 10 < expected.steps());
+    }
+
+    #[test]
+    fn test_preconditions() {
+        // Counting down from 0 never reaches 0, so neither program exits.
+        let program: Program<Instruction> = "set a 3\nsub a -4\nsub n 1\njnz n -2\nset b a"
+            .parse()
+            .unwrap();
+        let optimized = program.clone().replace_idioms();
+        assert!(optimized.to_string().contains("mad a 4 n"), "mad not found in:\n{optimized}");
+
+        // With e >= b, the dvt loop counts e up past b until it overflows.
+        let text = INPUT.replacen("set e 2", "set e 200000", 1);
+        let dvt = text
+            .parse::<Program<Instruction>>()
+            .unwrap()
+            .replace_idioms();
+        assert!(dvt.to_string().contains("dvt f d e b"), "dvt not found in:\n{dvt}");
+
+        for program in [program, optimized, dvt] {
+            let mut coprocessor = ExperimentalCoprocessor::new(program, OpCounts::default());
+            coprocessor.set_step_limit(Some(100_000));
+            assert!(coprocessor.execute().is_err());
+        }
+    }
+
+    #[test]
+    fn test_no_unreachable() {
+        let program: Program<Instruction> = INPUT.parse().unwrap();
+        let diagnostics = program.replace_idioms().lint(&["a"]);
+        assert!(
+            diagnostics
+                .iter()
+                .all(|d| d.kind != DiagnosticKind::Unreachable),
+            "{diagnostics:?}"
+        );
+    }
+
+    #[test]
+    fn test_same_as_optimized() {
+        for b in [81, 97, 91, 49] {
+            let text = INPUT.replacen("set b 81", &format!("set b {b}"), 1);
+            let program: Program<Instruction> = text.parse().unwrap();
+            let replaced = run(&program.clone().replace_idioms(), &[]);
+            let optimized = run(&program.optimize().unwrap(), &[]);
+            assert_eq!(optimized.register("h"), replaced.register("h"), "b = {b}");
+        }
+    }
+
+    #[test]
+    fn test_not_replaced() {
+        // Not a loop that can be replaced: `a` is also the counter.
+        let program: Program<Instruction> = "set a 5\nsub a -1\nsub a 2\njnz a -2".parse().unwrap();
+        assert_eq!(program.clone().replace_idioms(), program);
+
+        // The loop can be entered in the middle.
+        let program: Program<Instruction> = "jnz b 2\nsub a -4\nsub n 1\njnz n -2".parse().unwrap();
+        assert_eq!(program.clone().replace_idioms(), program);
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::helpers::duet::{InstructionSet, Program, Register, Value};
+
+/// [`InstructionSet`] whose programs can be checked for likely mistakes; see [`Program::lint`].
+pub trait Lint: InstructionSet {
+    /// Returns the registers the instruction reads.
+    fn reads(&self) -> Vec<&Register>;
+
+    /// Returns the register the instruction writes, if any.
+    fn writes(&self) -> Option<&Register>;
+
+    /// Returns the value the instruction divides by, if any.
+    fn divisor(&self) -> Option<&Value> {
+        None
+    }
+
+    /// Returns whether the instruction sends or receives values, if it does.
+    fn io(&self) -> Option<Io> {
+        None
+    }
+}
+
+/// Communication performed by an instruction; see [`Lint::io`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Io {
+    Send,
+    Receive,
+}
+
+/// Likely mistake found in a program by [`Program::lint`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Diagnostic {
+    /// Instruction pointer of the offending instruction.
+    pub ip: usize,
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DiagnosticKind {
+    /// A register may be read before it is ever written, relying on its initial value of 0.
+    ReadBeforeWrite { register: String },
+
+    /// The instruction can never be executed.
+    Unreachable,
+
+    /// A jump with a constant offset lands outside the program, other than right after
+    /// its last instruction (where programs usually jump to exit).
+    JumpOutside { target: i64 },
+
+    /// The instruction divides by a value that may be zero.
+    DivisionByZero { divisor: String },
+
+    /// The instruction receives values, but no instruction sends any.
+    ReceiveWithoutSend,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.ip)?;
+        match &self.kind {
+            DiagnosticKind::ReadBeforeWrite { register } => {
+                write!(f, "register {register} may be read before being written")
+            },
+            DiagnosticKind::Unreachable => write!(f, "unreachable instruction"),
+            DiagnosticKind::JumpOutside { target } => {
+                write!(f, "jump lands outside the program (at {target})")
+            },
+            DiagnosticKind::DivisionByZero { divisor } => {
+                write!(f, "division by {divisor}, which may be zero")
+            },
+            DiagnosticKind::ReceiveWithoutSend => {
+                write!(f, "values are received, but never sent")
+            },
+        }
+    }
+}
+
+impl<I> Program<I>
+where
+    I: Lint,
+{
+    /// Checks the program for likely mistakes, returning diagnostics sorted by instruction.
+    ///
+    /// `inputs` are the registers initialized before the program runs (e.g. `p` for day 18's
+    /// networked programs), which can be read without being written first.
+    pub fn lint(&self, inputs: &[&str]) -> Vec<Diagnostic> {
+        let successors = self.successors();
+        let reachable = self.reachable(&successors);
+        let mut diagnostics = BTreeSet::new();
+        let mut report = |ip, kind| {
+            diagnostics.insert(Diagnostic { ip, kind });
+        };
+
+        for (ip, instruction) in self.instructions().iter().enumerate() {
+            if !reachable[ip] {
+                report(ip, DiagnosticKind::Unreachable);
+            }
+
+            let target = instruction
+                .jump()
+                .filter(|jump| jump.is_taken() != Some(false))
+                .and_then(|jump| jump.target(ip as i64));
+            if let Some(target) = target.filter(|&target| target < 0 || target > self.len() as i64)
+            {
+                report(ip, DiagnosticKind::JumpOutside { target });
+            }
+
+            if let Some(divisor) = instruction.divisor() {
+                if matches!(divisor, Value::Number(0) | Value::Register(_)) {
+                    report(ip, DiagnosticKind::DivisionByZero { divisor: divisor.to_string() });
+                }
+            }
+        }
+
+        let sends = self.instructions().iter().any(|i| i.io() == Some(Io::Send));
+        for (ip, instruction) in self.instructions().iter().enumerate() {
+            if !sends && instruction.io() == Some(Io::Receive) {
+                report(ip, DiagnosticKind::ReceiveWithoutSend);
+            }
+        }
+
+        for (ip, register) in self.reads_before_writes(&successors, &reachable, inputs) {
+            report(ip, DiagnosticKind::ReadBeforeWrite { register: register.to_string() });
+        }
+
+        diagnostics.into_iter().collect()
+    }
+
+    /// Returns the instruction pointers that can be executed after each instruction.
+    ///
+    /// Jumps with a register offset can land anywhere.
+    fn successors(&self) -> Vec<Vec<usize>> {
+        let len = self.len() as i64;
+        let in_program = |ip: i64| (0..len).contains(&ip).then_some(ip as usize);
+
+        self.instructions()
+            .iter()
+            .enumerate()
+            .map(|(ip, instruction)| {
+                let next = in_program(ip as i64 + 1);
+                let Some(jump) = instruction.jump() else {
+                    return next.into_iter().collect();
+                };
+                let targets: Vec<usize> = match jump.target(ip as i64) {
+                    Some(target) => in_program(target).into_iter().collect(),
+                    None => (0..self.len()).collect(),
+                };
+                match jump.is_taken() {
+                    Some(true) => targets,
+                    Some(false) => next.into_iter().collect(),
+                    None => next.into_iter().chain(targets).collect(),
+                }
+            })
+            .collect()
+    }
+
+    fn reachable(&self, successors: &[Vec<usize>]) -> Vec<bool> {
+        let mut reachable = vec![false; self.len()];
+        let mut stack = vec![0];
+        while let Some(ip) = stack.pop() {
+            if ip < self.len() && !reachable[ip] {
+                reachable[ip] = true;
+                stack.extend(&successors[ip]);
+            }
+        }
+        reachable
+    }
+
+    /// Finds registers that may not have been written when read, by propagating
+    /// the set of possibly unwritten registers along all paths.
+    fn reads_before_writes(
+        &self,
+        successors: &[Vec<usize>],
+        reachable: &[bool],
+        inputs: &[&str],
+    ) -> Vec<(usize, &Register)> {
+        let instructions = self.instructions();
+        let mut unwritten: Vec<Option<BTreeSet<usize>>> = vec![None; self.len()];
+        if !self.is_empty() {
+            unwritten[0] = Some(
+                self.names()
+                    .iter()
+                    .filter(|register| !inputs.contains(&register.name()))
+                    .map(Register::index)
+                    .collect(),
+            );
+        }
+
+        let mut stack = vec![0];
+        while let Some(ip) = stack.pop() {
+            let Some(mut state) = unwritten.get(ip).cloned().flatten() else {
+                continue;
+            };
+            if let Some(register) = instructions[ip].writes() {
+                state.remove(&register.index());
+            }
+            for &successor in &successors[ip] {
+                let merged = match &unwritten[successor] {
+                    Some(existing) if existing.is_superset(&state) => continue,
+                    Some(existing) => existing | &state,
+                    None => state.clone(),
+                };
+                unwritten[successor] = Some(merged);
+                stack.push(successor);
+            }
+        }
+
+        instructions
+            .iter()
+            .enumerate()
+            .filter(|&(ip, _)| reachable[ip])
+            .flat_map(|(ip, instruction)| {
+                let unwritten = unwritten[ip].as_ref();
+                instruction
+                    .reads()
+                    .into_iter()
+                    .filter(move |register| {
+                        unwritten.is_some_and(|u| u.contains(&register.index()))
+                    })
+                    .map(move |register| (ip, register))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+    use crate::day_18;
+    use crate::helpers::duet::tests::TestInstruction;
+
+    impl Lint for TestInstruction {
+        fn reads(&self) -> Vec<&Register> {
+            match self {
+                Self::Add(register, value) => [Some(register), value.register()]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+                Self::Jnz(value, offset) => [value.register(), offset.register()]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+            }
+        }
+
+        fn writes(&self) -> Option<&Register> {
+            match self {
+                Self::Add(register, _) => Some(register),
+                Self::Jnz(..) => None,
+            }
+        }
+    }
+
+    fn kinds<I>(program: &str, inputs: &[&str]) -> Vec<(usize, DiagnosticKind)>
+    where
+        I: Lint,
+    {
+        let program: Program<I> = program.parse().unwrap();
+        program
+            .lint(inputs)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.ip, diagnostic.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_read_before_write() {
+        let read = |register: &str| DiagnosticKind::ReadBeforeWrite { register: register.into() };
+
+        // `b` is only written on one path before being read.
+        let program = "set a 0\njgz a 2\nset b 1\nset c b\nadd c x\nadd d 1";
+        assert_eq!(kinds::<day_18::Instruction>(program, &["x"]), [(3, read("b")), (5, read("d"))]);
+
+        let program = "set a 1\nset b a\nrcv c\nadd c b\nmod c p\nsnd c";
+        assert_eq!(
+            kinds::<day_18::Instruction>(program, &["p"]),
+            [(4, DiagnosticKind::DivisionByZero { divisor: "p".into() })]
+        );
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let program = "add a 1\njnz 1 3\nadd b 1\nadd c 1\njnz 0 -4\nadd d 1";
+        assert_eq!(
+            kinds::<TestInstruction>(program, &[]),
+            [
+                (0, DiagnosticKind::ReadBeforeWrite { register: "a".into() }),
+                (2, DiagnosticKind::Unreachable),
+                (3, DiagnosticKind::Unreachable),
+                (5, DiagnosticKind::ReadBeforeWrite { register: "d".into() }),
+            ]
+        );
+
+        // Jumps with a register offset can land anywhere.
+        let program = "add o 3\njnz 1 o\nadd b 1";
+        assert!(
+            !kinds::<TestInstruction>(program, &["o"]).contains(&(2, DiagnosticKind::Unreachable))
+        );
+    }
+
+    #[test]
+    fn test_jump_outside() {
+        let program = "set a 1\njgz a 2\njgz 1 -3\njgz 1 10\njgz 0 10";
+        assert_eq!(
+            kinds::<day_18::Instruction>(program, &[]),
+            [
+                (2, DiagnosticKind::JumpOutside { target: -1 }),
+                (3, DiagnosticKind::JumpOutside { target: 13 }),
+                (4, DiagnosticKind::Unreachable),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_io() {
+        let program = "set a 3\nmod a 0\nrcv a";
+        assert_eq!(
+            kinds::<day_18::Instruction>(program, &[]),
+            [
+                (1, DiagnosticKind::DivisionByZero { divisor: "0".into() }),
+                (2, DiagnosticKind::ReceiveWithoutSend)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let program: Program<day_18::Instruction> = "rcv a\nadd b 1\njgz 1 -5".parse().unwrap();
+        let diagnostics = program
+            .lint(&[])
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            diagnostics,
+            [
+                "0: values are received, but never sent",
+                "1: register b may be read before being written",
+                "2: jump lands outside the program (at -3)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,991 @@
+use std::cell::{RefCell, RefMut};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use anyhow::{bail, ensure};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::duet::snapshot::{Snapshot, SnapshotContext};
+use crate::helpers::duet::{InstructionResult, InstructionSet, Queue, Vm};
+
+/// [`Queue`] shared by the programs of a [`Network`].
+#[derive(Debug, Clone)]
+pub enum SharedQueue {
+    /// Queue shared by programs running on the same thread.
+    Local(Rc<RefCell<Queue>>),
+
+    /// Queue shared by programs running on separate threads; see [`NetworkBuilder::run_threaded`].
+    Synchronized(Arc<Mutex<Queue>>),
+}
+
+impl SharedQueue {
+    pub fn new(queue: Queue) -> Self {
+        Self::Local(Rc::new(RefCell::new(queue)))
+    }
+
+    /// Gives access to the queue's contents until the returned guard is dropped.
+    pub fn lock(&self) -> QueueGuard<'_> {
+        match self {
+            Self::Local(queue) => QueueGuard::Local(queue.borrow_mut()),
+            Self::Synchronized(queue) => QueueGuard::Synchronized(queue.lock().unwrap()),
+        }
+    }
+}
+
+impl Default for SharedQueue {
+    fn default() -> Self {
+        Self::new(Queue::default())
+    }
+}
+
+/// Access to the contents of a [`SharedQueue`]; see [`SharedQueue::lock`].
+pub enum QueueGuard<'a> {
+    Local(RefMut<'a, Queue>),
+    Synchronized(MutexGuard<'a, Queue>),
+}
+
+impl Deref for QueueGuard<'_> {
+    type Target = Queue;
+
+    fn deref(&self) -> &Queue {
+        match self {
+            Self::Local(queue) => queue,
+            Self::Synchronized(queue) => queue,
+        }
+    }
+}
+
+impl fmt::Display for QueueGuard<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+impl DerefMut for QueueGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Queue {
+        match self {
+            Self::Local(queue) => queue,
+            Self::Synchronized(queue) => queue,
+        }
+    }
+}
+
+/// How values sent by the programs of a [`Network`] are routed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum Topology {
+    /// Each program sends to the next one; the last program sends to the first.
+    #[default]
+    Ring,
+
+    /// Each program sends to every other program.
+    Broadcast,
+
+    /// Each program sends to the programs listed for its ID. Programs that are
+    /// not listed send their values nowhere, and a program cannot be listed twice.
+    Explicit(BTreeMap<usize, Vec<usize>>),
+}
+
+impl Topology {
+    fn targets(&self, id: usize, size: usize) -> Vec<usize> {
+        match self {
+            Self::Ring => vec![(id + 1) % size],
+            Self::Broadcast => (0..size).filter(|&target| target != id).collect(),
+            Self::Explicit(targets) => targets.get(&id).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// Reason why a program connected to [`Ports`] cannot make progress.
+///
+/// Queues are identified by the ID of the program that receives from them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Blocked {
+    /// Program is trying to receive from its empty queue.
+    Receiving { queue: usize },
+
+    /// Program is trying to send to a full queue.
+    Sending { queue: usize },
+}
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Receiving { queue } => write!(f, "blocked receiving from queue {queue} (empty)"),
+            Self::Sending { queue } => write!(f, "blocked sending to queue {queue} (full)"),
+        }
+    }
+}
+
+/// Endpoints connecting a program to the other programs of a [`Network`].
+///
+/// A program receives values from its own input queue and sends each value
+/// to the input queues of all its targets.
+#[derive(Debug)]
+pub struct Ports {
+    id: usize,
+    input: SharedQueue,
+    outputs: Vec<(usize, SharedQueue)>,
+    bounded: bool,
+    sent: usize,
+    received: usize,
+    blocked: Option<Blocked>,
+    coordinator: Option<Arc<Coordinator>>,
+    epoch: u64,
+}
+
+impl Ports {
+    /// Creates ports for program `id`, receiving from `input` and sending to
+    /// `outputs` (as pairs of target program ID and its input queue).
+    pub fn new(id: usize, input: SharedQueue, outputs: Vec<(usize, SharedQueue)>) -> Self {
+        let bounded = outputs
+            .iter()
+            .any(|(_, output)| output.lock().capacity().is_some());
+        Self {
+            id,
+            input,
+            outputs,
+            bounded,
+            sent: 0,
+            received: 0,
+            blocked: None,
+            coordinator: None,
+            epoch: 0,
+        }
+    }
+
+    /// Creates ports for a lone program sending values to itself.
+    pub fn looped() -> Self {
+        let queue = SharedQueue::default();
+        Self::new(0, queue.clone(), vec![(0, queue)])
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Locks the input queue, giving access to its contents.
+    pub fn input(&self) -> QueueGuard<'_> {
+        self.input.lock()
+    }
+
+    /// Sends `value` to all targets. If any target queue is full, nothing is sent
+    /// and `false` is returned.
+    pub fn send(&mut self, value: i64) -> bool {
+        self.synchronized(|ports| {
+            let full = ports.bounded.then(|| {
+                ports
+                    .outputs
+                    .iter()
+                    .find(|(_, output)| output.lock().is_full())
+            });
+            if let Some(Some((queue, _))) = full {
+                ports.blocked = Some(Blocked::Sending { queue: *queue });
+                return None;
+            }
+
+            for (_, output) in &ports.outputs {
+                output.lock().push(value);
+            }
+            ports.sent += 1;
+            ports.blocked = None;
+            Some(())
+        })
+        .is_some()
+    }
+
+    /// Receives the next value from the input queue, if any.
+    pub fn receive(&mut self) -> Option<i64> {
+        self.synchronized(|ports| {
+            let value = ports.input().pop();
+            match value {
+                Some(_) => {
+                    ports.received += 1;
+                    ports.blocked = None;
+                },
+                None => ports.blocked = Some(Blocked::Receiving { queue: ports.id }),
+            }
+            value
+        })
+    }
+
+    /// Takes back `value` from all targets, undoing the last [`send`](Ports::send).
+    ///
+    /// Fails without changing anything if `value` is not the last value of every target
+    /// queue (e.g. because it was already received).
+    pub fn unsend(&mut self, value: i64) -> Result<(), anyhow::Error> {
+        let mut outputs = self
+            .outputs
+            .iter()
+            .map(|(_, output)| output.lock())
+            .collect_vec();
+        ensure!(
+            outputs.iter().all(|output| output.last() == Some(value)),
+            "sent value was already received"
+        );
+        for output in &mut outputs {
+            output.pop_last();
+        }
+        self.sent -= 1;
+        Ok(())
+    }
+
+    /// Puts `value` back in the input queue, undoing the last [`receive`](Ports::receive).
+    pub fn unreceive(&mut self, value: i64) {
+        self.input().unpop(value);
+        self.received -= 1;
+    }
+
+    /// Returns the number of values sent (a value sent to multiple targets is counted once).
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Returns why the last send or receive could not complete, if it could not.
+    pub fn blocked(&self) -> Option<Blocked> {
+        self.blocked
+    }
+
+    /// Waits until another program of a threaded network makes progress, after which
+    /// the operation that could not complete can be retried.
+    ///
+    /// Returns `false` if the network was stopped (because all its programs are blocked
+    /// or one of them failed) or if these ports are not part of a threaded network.
+    pub fn wait(&self) -> bool {
+        self.coordinator
+            .as_ref()
+            .is_some_and(|coordinator| coordinator.wait(self.epoch))
+    }
+
+    /// Saves the state of the ports, including the contents of the input queue.
+    pub fn snapshot(&self) -> PortsSnapshot {
+        PortsSnapshot {
+            id: self.id,
+            input: self.input().iter().copied().collect(),
+            sent: self.sent,
+            received: self.received,
+            blocked: self.blocked,
+        }
+    }
+
+    /// Replaces the state of the ports with the state saved in `snapshot`.
+    ///
+    /// Only the input queue is restored: in a network, the other queues are the inputs
+    /// of other programs.
+    pub fn restore(&mut self, snapshot: PortsSnapshot) -> Result<(), anyhow::Error> {
+        ensure!(
+            snapshot.id == self.id,
+            "snapshot of program {} restored in program {}",
+            snapshot.id,
+            self.id
+        );
+
+        let mut input = self.input.lock();
+        let capacity = input.capacity().unwrap_or(usize::MAX);
+        ensure!(snapshot.input.len() <= capacity, "too many values for queue {}", self.id);
+        input.clear();
+        for value in snapshot.input {
+            input.push(value);
+        }
+        drop(input);
+
+        self.sent = snapshot.sent;
+        self.received = snapshot.received;
+        self.blocked = snapshot.blocked;
+        Ok(())
+    }
+
+    /// Performs a send or receive operation, notifying other threads if it succeeds.
+    fn synchronized<T, F>(&mut self, operation: F) -> Option<T>
+    where
+        F: FnOnce(&mut Self) -> Option<T>,
+    {
+        let Some(coordinator) = self.coordinator.clone() else {
+            return operation(self);
+        };
+
+        let mut state = coordinator.state.lock().unwrap();
+        let result = operation(self);
+        match result {
+            Some(_) => {
+                state.progress();
+                coordinator.condvar.notify_all();
+            },
+            None => self.epoch = state.epoch,
+        }
+        result
+    }
+}
+
+/// Saved state of [`Ports`]; see [`Ports::snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortsSnapshot {
+    pub id: usize,
+    pub input: Vec<i64>,
+    pub sent: usize,
+    pub received: usize,
+    pub blocked: Option<Blocked>,
+}
+
+/// Keeps track of the threads of a threaded network, to detect when all of them are blocked.
+///
+/// Every successful send or receive starts a new epoch. Blocked threads wait for the epoch
+/// to change before retrying; if all threads that have not exited are waiting during the
+/// same epoch, none of them can ever make progress.
+#[derive(Debug)]
+struct Coordinator {
+    size: usize,
+    state: Mutex<CoordinatorState>,
+    condvar: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct CoordinatorState {
+    epoch: u64,
+    waiting: usize,
+    exited: usize,
+    stopped: bool,
+}
+
+impl CoordinatorState {
+    fn progress(&mut self) {
+        self.epoch += 1;
+        self.waiting = 0;
+    }
+}
+
+impl Coordinator {
+    fn new(size: usize) -> Self {
+        Self { size, state: Mutex::default(), condvar: Condvar::new() }
+    }
+
+    fn wait(&self, epoch: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            return false;
+        }
+        if state.epoch != epoch {
+            return true;
+        }
+
+        state.waiting += 1;
+        if state.waiting + state.exited == self.size {
+            state.stopped = true;
+            self.condvar.notify_all();
+            return false;
+        }
+
+        let state = self
+            .condvar
+            .wait_while(state, |state| state.epoch == epoch && !state.stopped)
+            .unwrap();
+        !state.stopped
+    }
+
+    fn exit(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.exited += 1;
+        state.progress();
+        self.condvar.notify_all();
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.condvar.notify_all();
+    }
+}
+
+/// [`InstructionSet::Context`] of programs that can be connected in a [`Network`].
+pub trait NetworkContext {
+    /// Returns the ports connecting the program to the network, if it is connected.
+    ///
+    /// Programs that are not connected never wait for other programs, and do not
+    /// send or receive any values.
+    fn ports(&self) -> Option<&Ports>;
+}
+
+/// Builder used to connect programs in a [`Network`].
+#[derive(Debug, Clone)]
+pub struct NetworkBuilder {
+    size: usize,
+    topology: Topology,
+    capacity: Option<usize>,
+}
+
+impl NetworkBuilder {
+    /// Creates a builder for a network of `size` programs, connected in a [ring](Topology::Ring)
+    /// through unbounded queues.
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "network must contain at least one program");
+        Self { size, topology: Topology::default(), capacity: None }
+    }
+
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// Limits the number of values each queue can hold; programs trying to send to
+    /// a full queue wait until the value can be sent.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "queue capacity must be positive");
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Builds the network, calling `create` to create the machine of each program from
+    /// its [`Ports`].
+    pub fn build<I, F>(self, mut create: F) -> Result<Network<I>, anyhow::Error>
+    where
+        I: InstructionSet,
+        I::Context: NetworkContext,
+        F: FnMut(Ports) -> Vm<I>,
+    {
+        let programs = self
+            .connections(SharedQueue::new)?
+            .into_iter()
+            .map(|(id, input, outputs)| create(Ports::new(id, input, outputs)))
+            .collect();
+        Ok(Network { programs })
+    }
+
+    /// Runs the network with each program on its own thread, until all programs have exited
+    /// or are blocked.
+    ///
+    /// `create` is called on each thread to create the program's machine from its [`Ports`].
+    /// If a program fails, the other programs are stopped as soon as they are blocked and
+    /// the first error is returned.
+    pub fn run_threaded<I, F>(self, create: F) -> Result<NetworkReport, anyhow::Error>
+    where
+        I: InstructionSet,
+        I::Context: NetworkContext,
+        F: Fn(Ports) -> Vm<I> + Sync,
+    {
+        let coordinator = Arc::new(Coordinator::new(self.size));
+        let connections = self.connections(|queue| Arc::new(Mutex::new(queue)))?;
+
+        let reports = thread::scope(|scope| {
+            let create = &create;
+            let coordinator = &coordinator;
+            let handles = connections
+                .into_iter()
+                .map(|(id, input, outputs)| {
+                    scope.spawn(move || {
+                        let outputs = outputs
+                            .into_iter()
+                            .map(|(target, output)| (target, SharedQueue::Synchronized(output)))
+                            .collect();
+                        let mut ports = Ports::new(id, SharedQueue::Synchronized(input), outputs);
+                        ports.coordinator = Some(Arc::clone(coordinator));
+                        run_thread(create(ports), coordinator)
+                    })
+                })
+                .collect_vec();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("duet program thread panicked"))
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+
+        Ok(reports.into_iter().collect())
+    }
+
+    /// Returns the ID, input queue and output queues of each program, creating queues with `wrap`.
+    #[allow(clippy::type_complexity)]
+    fn connections<Q, F>(&self, wrap: F) -> Result<Vec<(usize, Q, Vec<(usize, Q)>)>, anyhow::Error>
+    where
+        Q: Clone,
+        F: Fn(Queue) -> Q,
+    {
+        let queues: Vec<Q> = (0..self.size)
+            .map(|_| wrap(self.capacity.map(Queue::bounded).unwrap_or_default()))
+            .collect();
+
+        (0..self.size)
+            .map(|id| {
+                let targets = self.topology.targets(id, self.size);
+                if let Some(&target) = targets.iter().find(|&&target| target >= self.size) {
+                    bail!("program {id} sends to non-existent program {target}");
+                }
+                if let Some(target) = targets.iter().duplicates().next() {
+                    bail!("program {id} sends to program {target} more than once");
+                }
+                let outputs = targets
+                    .into_iter()
+                    .map(|target| (target, queues[target].clone()))
+                    .collect();
+                Ok((id, queues[id].clone(), outputs))
+            })
+            .collect()
+    }
+}
+
+/// Runs a program of a threaded network, returning its final report.
+fn run_thread<I>(mut vm: Vm<I>, coordinator: &Coordinator) -> Result<ProgramReport, anyhow::Error>
+where
+    I: InstructionSet,
+    I::Context: NetworkContext,
+{
+    let _guard = StopOnPanic(coordinator);
+    loop {
+        match vm.execute_next() {
+            Ok(InstructionResult::Exited) => {
+                coordinator.exit();
+                break;
+            },
+            Ok(InstructionResult::Waiting) => {
+                if !vm.context().ports().is_some_and(Ports::wait) {
+                    break;
+                }
+            },
+            Ok(_) => {},
+            Err(err) => {
+                coordinator.stop();
+                return Err(err);
+            },
+        }
+    }
+
+    Ok(ProgramReport::new(&vm))
+}
+
+/// Stops a threaded network if one of its threads panics, so that other threads do not
+/// wait forever.
+struct StopOnPanic<'a>(&'a Coordinator);
+
+impl Drop for StopOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.stop();
+        }
+    }
+}
+
+/// Programs exchanging values through queues; see [`NetworkBuilder`].
+pub struct Network<I>
+where
+    I: InstructionSet,
+{
+    programs: Vec<Vm<I>>,
+}
+
+impl<I> Network<I>
+where
+    I: InstructionSet,
+    I::Context: NetworkContext,
+{
+    pub fn programs(&self) -> &[Vm<I>] {
+        &self.programs
+    }
+
+    pub fn programs_mut(&mut self) -> &mut [Vm<I>] {
+        &mut self.programs
+    }
+
+    pub fn len(&self) -> usize {
+        self.programs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.programs.is_empty()
+    }
+
+    /// Returns the number of values sent by program `id`.
+    pub fn sent(&self, id: usize) -> usize {
+        self.programs[id].context().ports().map_or(0, Ports::sent)
+    }
+
+    /// Returns the number of values received by program `id`.
+    pub fn received(&self, id: usize) -> usize {
+        self.programs[id]
+            .context()
+            .ports()
+            .map_or(0, Ports::received)
+    }
+
+    /// Runs each program in turn until it stalls, stopping when no program can make progress.
+    pub fn run(&mut self) -> Result<NetworkReport, anyhow::Error> {
+        loop {
+            let mut progressed = false;
+            for vm in &mut self.programs {
+                // Steps that returned `Waiting` did not make progress.
+                let steps = vm.steps();
+                let waited = vm.execute()? == InstructionResult::Waiting;
+                progressed |= vm.steps() - steps > u64::from(waited);
+            }
+            if !progressed {
+                return Ok(self.report());
+            }
+        }
+    }
+
+    /// Returns the current status of every program.
+    pub fn report(&self) -> NetworkReport {
+        self.programs.iter().map(ProgramReport::new).collect()
+    }
+}
+
+/// Saved state of a [`Network`], including the contents of all its queues.
+pub type NetworkSnapshot<C> = Vec<Snapshot<C>>;
+
+impl<I> Network<I>
+where
+    I: InstructionSet,
+    I::Context: NetworkContext + SnapshotContext,
+{
+    pub fn snapshot(&self) -> NetworkSnapshot<<I::Context as SnapshotContext>::Snapshot> {
+        self.programs.iter().map(Vm::snapshot).collect()
+    }
+
+    /// Replaces the state of every program with the state saved in `snapshot`.
+    ///
+    /// The network must have been built the same way as the network the snapshot was taken of.
+    /// If any program's snapshot is invalid, the whole network is left untouched.
+    pub fn restore(
+        &mut self,
+        snapshot: NetworkSnapshot<<I::Context as SnapshotContext>::Snapshot>,
+    ) -> Result<(), anyhow::Error> {
+        ensure!(
+            snapshot.len() == self.programs.len(),
+            "snapshot of a network of {} programs restored in a network of {}",
+            snapshot.len(),
+            self.programs.len()
+        );
+
+        let previous = self.snapshot();
+        for (id, snapshot) in snapshot.into_iter().enumerate() {
+            if let Err(err) = self.programs[id].restore_state(snapshot) {
+                for (vm, snapshot) in self.programs.iter_mut().zip(previous).take(id) {
+                    vm.restore_state(snapshot)
+                        .expect("previous state should be restorable");
+                }
+                return Err(err.context(format!("invalid snapshot of program {id}")));
+            }
+        }
+        self.programs.iter_mut().for_each(Vm::forget_past);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProgramStatus {
+    Exited,
+    Blocked(Blocked),
+
+    /// Program is neither exited nor blocked on a queue.
+    Running,
+}
+
+impl fmt::Display for ProgramStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exited => write!(f, "exited"),
+            Self::Blocked(blocked) => write!(f, "{blocked}"),
+            Self::Running => write!(f, "running"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct ProgramReport {
+    status: ProgramStatus,
+    sent: usize,
+    received: usize,
+}
+
+impl ProgramReport {
+    fn new<I>(vm: &Vm<I>) -> Self
+    where
+        I: InstructionSet,
+        I::Context: NetworkContext,
+    {
+        let ports = vm.context().ports();
+        let status = match vm.program().get(vm.ip()) {
+            None => ProgramStatus::Exited,
+            Some(_) => ports
+                .and_then(Ports::blocked)
+                .map_or(ProgramStatus::Running, ProgramStatus::Blocked),
+        };
+        Self {
+            status,
+            sent: ports.map_or(0, Ports::sent),
+            received: ports.map_or(0, Ports::received),
+        }
+    }
+}
+
+/// Status and counters of the programs of a [`Network`], as returned by [`Network::run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkReport {
+    statuses: Vec<ProgramStatus>,
+    sent: Vec<usize>,
+    received: Vec<usize>,
+}
+
+impl NetworkReport {
+    /// Returns the status of each program, indexed by program ID.
+    pub fn statuses(&self) -> &[ProgramStatus] {
+        &self.statuses
+    }
+
+    /// Returns the number of values sent by program `id`.
+    pub fn sent(&self, id: usize) -> usize {
+        self.sent[id]
+    }
+
+    /// Returns the number of values received by program `id`.
+    pub fn received(&self, id: usize) -> usize {
+        self.received[id]
+    }
+
+    /// Returns the ID of every blocked program along with the reason it is blocked.
+    pub fn blocked(&self) -> impl Iterator<Item = (usize, Blocked)> + '_ {
+        self.statuses
+            .iter()
+            .enumerate()
+            .filter_map(|(id, status)| match status {
+                ProgramStatus::Blocked(blocked) => Some((id, *blocked)),
+                _ => None,
+            })
+    }
+
+    /// Whether the network stopped because some programs are blocked forever,
+    /// rather than because all programs exited.
+    pub fn is_deadlock(&self) -> bool {
+        self.blocked().next().is_some()
+    }
+}
+
+impl FromIterator<ProgramReport> for NetworkReport {
+    fn from_iter<T>(iter: T) -> Self
+    where
+        T: IntoIterator<Item = ProgramReport>,
+    {
+        let (statuses, (sent, received)) = iter
+            .into_iter()
+            .map(|report| (report.status, (report.sent, report.received)))
+            .unzip();
+        Self { statuses, sent, received }
+    }
+}
+
+impl fmt::Display for NetworkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (id, status) in self.statuses.iter().enumerate() {
+            writeln!(
+                f,
+                "program {id}: {status} (sent: {}, received: {})",
+                self.sent[id], self.received[id]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+    use crate::day_18::{DuetInterpreter, Instruction};
+    use crate::helpers::duet::{Program, VmError};
+    use crate::input::day_18::INPUT;
+
+    fn network(program: &str, builder: NetworkBuilder) -> Network<Instruction> {
+        let program: Program<Instruction> = program.parse().unwrap();
+        builder
+            .build(|ports| DuetInterpreter::networked(program.clone(), ports))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut network = network("snd p\nrcv a", NetworkBuilder::new(3));
+
+        let report = network.run().unwrap();
+        assert!(!report.is_deadlock());
+        assert_eq!(&[ProgramStatus::Exited; 3], report.statuses());
+        let values: Vec<_> = network
+            .programs()
+            .iter()
+            .map(|vm| vm.register("a"))
+            .collect();
+        assert_eq!(vec![2, 0, 1], values);
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let builder = NetworkBuilder::new(3).topology(Topology::Broadcast);
+        let mut network = network("snd p\nrcv a\nrcv b\nrcv c", builder);
+
+        let report = network.run().unwrap();
+        assert!(report.is_deadlock());
+        let blocked: Vec<_> = report.blocked().collect();
+        assert_eq!(
+            vec![
+                (0, Blocked::Receiving { queue: 0 }),
+                (1, Blocked::Receiving { queue: 1 }),
+                (2, Blocked::Receiving { queue: 2 }),
+            ],
+            blocked
+        );
+        assert_eq!((1, 2), (network.sent(0), network.received(0)));
+        let program_2 = &network.programs()[2];
+        assert_eq!((0, 1), (program_2.register("a"), program_2.register("b")));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let builder = NetworkBuilder::new(2).capacity(1);
+        let mut network = network("snd 1\nsnd 2\nsnd 3", builder);
+
+        let report = network.run().unwrap();
+        assert_eq!(
+            "program 0: blocked sending to queue 1 (full) (sent: 1, received: 0)\n\
+             program 1: blocked sending to queue 0 (full) (sent: 1, received: 0)\n",
+            report.to_string()
+        );
+        assert_eq!(1, network.sent(0));
+    }
+
+    #[test]
+    fn test_explicit() {
+        let topology = Topology::Explicit(BTreeMap::from([(0, vec![1, 2])]));
+        let builder = NetworkBuilder::new(3).topology(topology);
+        let mut network = network("jgz p 3\nsnd 7\nsnd 8\nrcv a", builder);
+
+        let report = network.run().unwrap();
+        assert_eq!(
+            &[
+                ProgramStatus::Blocked(Blocked::Receiving { queue: 0 }),
+                ProgramStatus::Exited,
+                ProgramStatus::Exited,
+            ],
+            report.statuses()
+        );
+        assert_eq!(7, network.programs()[2].register("a"));
+
+        let topology = Topology::Explicit(BTreeMap::from([(0, vec![3])]));
+        let program: Program<Instruction> = "snd 1".parse().unwrap();
+        let err = NetworkBuilder::new(3)
+            .topology(topology)
+            .build(|ports| DuetInterpreter::networked(program.clone(), ports))
+            .err()
+            .unwrap();
+        assert_eq!("program 0 sends to non-existent program 3", err.to_string());
+
+        let topology = Topology::Explicit(BTreeMap::from([(0, vec![1, 1])]));
+        let err = NetworkBuilder::new(3)
+            .topology(topology)
+            .build(|ports| DuetInterpreter::networked(program.clone(), ports))
+            .err()
+            .unwrap();
+        assert_eq!("program 0 sends to program 1 more than once", err.to_string());
+    }
+
+    #[test]
+    fn test_infinite_loop_detection() {
+        let program: Program<Instruction> =
+            "jgz p 7\nsnd 1\nrcv b\nsnd 1\nrcv b\nsnd 0\njgz 1 4\nrcv a\nsnd a\njgz a -2"
+                .parse()
+                .unwrap();
+        let mut network = NetworkBuilder::new(2)
+            .build(|ports| {
+                let mut interpreter = DuetInterpreter::networked(program.clone(), ports);
+                interpreter.detect_infinite_loops(true);
+                interpreter
+            })
+            .unwrap();
+
+        let report = network.run().unwrap();
+        assert_eq!(&[ProgramStatus::Exited; 2], report.statuses());
+        assert_eq!(3, network.received(1));
+    }
+
+    #[test]
+    fn test_threaded() {
+        let programs = [
+            ("snd p\nrcv a", NetworkBuilder::new(3)),
+            ("snd p\nrcv a\nrcv b\nrcv c", NetworkBuilder::new(3).topology(Topology::Broadcast)),
+            ("snd 1\nsnd 2\nsnd 3", NetworkBuilder::new(2).capacity(1)),
+            (INPUT, NetworkBuilder::new(2)),
+        ];
+
+        for (program, builder) in programs {
+            let expected = network(program, builder.clone()).run().unwrap();
+
+            let program: Program<Instruction> = program.parse().unwrap();
+            let report = builder
+                .run_threaded(|ports| DuetInterpreter::networked(program.clone(), ports))
+                .unwrap();
+            assert_eq!(expected, report);
+        }
+    }
+
+    #[test]
+    fn test_threaded_error() {
+        let program: Program<Instruction> = "jgz p 2\nrcv a\njgz 1 0".parse().unwrap();
+        let err = NetworkBuilder::new(2)
+            .run_threaded(|ports| {
+                let mut interpreter = DuetInterpreter::networked(program.clone(), ports);
+                interpreter.set_step_limit(Some(10));
+                interpreter
+            })
+            .unwrap_err();
+        assert_eq!(Some(&VmError::StepLimitExceeded { limit: 10 }), err.downcast_ref());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut network = network(INPUT, NetworkBuilder::new(2));
+        for _ in 0..300 {
+            network.programs_mut()[0].execute_next().unwrap();
+        }
+        let json = serde_json::to_string(&network.snapshot()).unwrap();
+        let expected = network.run().unwrap();
+
+        let mut resumed = self::network(INPUT, NetworkBuilder::new(2));
+        resumed
+            .restore(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        assert_eq!(resumed.programs()[0].steps(), 300);
+        assert_eq!(resumed.sent(0), 20);
+        assert_eq!(
+            resumed.programs()[1]
+                .context()
+                .ports()
+                .unwrap()
+                .input()
+                .len(),
+            20
+        );
+        assert_eq!(resumed.run().unwrap(), expected);
+        assert_eq!(resumed.sent(1), 7_493);
+
+        let mut smaller = self::network(INPUT, NetworkBuilder::new(1));
+        let err = smaller
+            .restore(serde_json::from_str(&json).unwrap())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "snapshot of a network of 2 programs restored in a network of 1"
+        );
+
+        let mut invalid: NetworkSnapshot<_> = serde_json::from_str(&json).unwrap();
+        invalid[1].registers.insert("z".into(), 1);
+        let mut untouched = self::network(INPUT, NetworkBuilder::new(2));
+        untouched.programs_mut()[1].execute_next().unwrap();
+        let before = serde_json::to_string(&untouched.snapshot()).unwrap();
+        let err = untouched.restore(invalid).unwrap_err();
+        assert_eq!(err.to_string(), "invalid snapshot of program 1");
+        assert_eq!(serde_json::to_string(&untouched.snapshot()).unwrap(), before);
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt;
+use std::time::Duration;
+
+use itertools::Itertools;
+
+use crate::helpers::duet::cfg::ControlFlowGraph;
+use crate::helpers::duet::{InstructionResult, InstructionSet, Program};
+
+/// Execution profile of a program, collected by a [`Vm`](crate::helpers::duet::Vm) while
+/// [profiling](crate::helpers::duet::Vm::start_profiling).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    hits: Vec<u64>,
+    taken: Vec<u64>,
+    time: Vec<Duration>,
+}
+
+impl Profile {
+    pub fn new(len: usize) -> Self {
+        Self { hits: vec![0; len], taken: vec![0; len], time: vec![Duration::ZERO; len] }
+    }
+
+    pub(super) fn record(&mut self, ip: usize, result: &InstructionResult, elapsed: Duration) {
+        self.hits[ip] += 1;
+        self.time[ip] += elapsed;
+        if let InstructionResult::JmpOffset(_) = result {
+            self.taken[ip] += 1;
+        }
+    }
+
+    /// Returns the number of times the instruction at `ip` was executed.
+    pub fn hits(&self, ip: usize) -> u64 {
+        self.hits.get(ip).copied().unwrap_or_default()
+    }
+
+    /// Returns the number of times the instruction at `ip` jumped.
+    pub fn jumps_taken(&self, ip: usize) -> u64 {
+        self.taken.get(ip).copied().unwrap_or_default()
+    }
+
+    /// Returns the time spent executing the instruction at `ip`.
+    pub fn time(&self, ip: usize) -> Duration {
+        self.time.get(ip).copied().unwrap_or_default()
+    }
+
+    pub fn total_hits(&self) -> u64 {
+        self.hits.iter().sum()
+    }
+
+    pub fn total_time(&self) -> Duration {
+        self.time.iter().sum()
+    }
+
+    /// Returns the profile of each basic block of `cfg`, indexed by block ID.
+    pub fn blocks<I>(&self, cfg: &ControlFlowGraph<'_, I>) -> Vec<Hotspot>
+    where
+        I: InstructionSet,
+    {
+        cfg.blocks()
+            .iter()
+            .map(|block| Hotspot {
+                entries: self.hits(block.start),
+                hits: block.ips().map(|ip| self.hits(ip)).sum(),
+                time: block.ips().map(|ip| self.time(ip)).sum(),
+            })
+            .collect()
+    }
+
+    /// Returns the index and profile of every loop of `cfg`, hottest (most hits) first.
+    pub fn hottest_loops<I>(&self, cfg: &ControlFlowGraph<'_, I>) -> Vec<(usize, Hotspot)>
+    where
+        I: InstructionSet,
+    {
+        let blocks = self.blocks(cfg);
+        cfg.loops()
+            .iter()
+            .enumerate()
+            .map(|(index, l)| {
+                let hotspot = Hotspot {
+                    entries: blocks[l.header].entries,
+                    hits: l.blocks.iter().map(|&id| blocks[id].hits).sum(),
+                    time: l.blocks.iter().map(|&id| blocks[id].time).sum(),
+                };
+                (index, hotspot)
+            })
+            .sorted_by_key(|&(index, hotspot)| (std::cmp::Reverse(hotspot.hits), index))
+            .collect()
+    }
+
+    /// Returns a report of the profile, showing the hits of each instruction of `program`
+    /// next to its listing, followed by the time spent in basic blocks and the hottest loops.
+    pub fn report<'a, I>(&'a self, program: &'a Program<I>) -> Report<'a, I> {
+        Report { profile: self, program }
+    }
+}
+
+/// Profile of a part of a program, like a basic block or a loop.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Hotspot {
+    /// Number of times the first instruction was executed.
+    pub entries: u64,
+
+    /// Total number of instructions executed.
+    pub hits: u64,
+
+    pub time: Duration,
+}
+
+/// Report of a [`Profile`]. See [`Profile::report`].
+#[derive(Debug)]
+pub struct Report<'a, I> {
+    profile: &'a Profile,
+    program: &'a Program<I>,
+}
+
+impl<'a, I> fmt::Display for Report<'a, I>
+where
+    I: InstructionSet,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (profile, program) = (self.profile, self.program);
+        let total_hits = profile.total_hits().max(1);
+        let total_time = profile.total_time().max(Duration::from_nanos(1));
+        let percent = |hits: u64| 100.0 * hits as f64 / total_hits as f64;
+        let time_percent = |time: Duration| 100.0 * time.as_secs_f64() / total_time.as_secs_f64();
+
+        let hits_width = profile.hits.iter().max().unwrap_or(&0).to_string().len();
+        let ip_width = program.len().saturating_sub(1).to_string().len();
+        let instruction_width = program
+            .instructions()
+            .iter()
+            .map(|instruction| instruction.to_string().len())
+            .max()
+            .unwrap_or_default();
+
+        for (ip, instruction) in program.instructions().iter().enumerate() {
+            let (hits, instruction) = (profile.hits(ip), instruction.to_string());
+            let line = format!(
+                "{hits:>hits_width$} {:>5.1}%  {ip:>ip_width$}: {instruction:<instruction_width$}",
+                percent(hits)
+            );
+            match program.instructions()[ip].jump().filter(|_| hits > 0) {
+                Some(_) => {
+                    let taken = profile.jumps_taken(ip);
+                    let ratio = 100.0 * taken as f64 / hits as f64;
+                    writeln!(f, "{line}  ; taken {taken}/{hits} ({ratio:.1}%)")?
+                },
+                None => writeln!(f, "{}", line.trim_end())?,
+            }
+        }
+
+        let cfg = ControlFlowGraph::new(program);
+        writeln!(f, "\nblocks:")?;
+        for (id, (block, hotspot)) in cfg.blocks().iter().zip(profile.blocks(&cfg)).enumerate() {
+            writeln!(
+                f,
+                "    b{id} ({}-{}): {} entries, {} hits, {:.1?} ({:.1}%)",
+                block.start,
+                block.end - 1,
+                hotspot.entries,
+                hotspot.hits,
+                hotspot.time,
+                time_percent(hotspot.time)
+            )?;
+        }
+
+        let loops = profile.hottest_loops(&cfg);
+        if !loops.is_empty() {
+            writeln!(f, "\nhottest loops:")?;
+        }
+        for (index, hotspot) in loops {
+            let l = &cfg.loops()[index];
+            writeln!(
+                f,
+                "    loop {index} (header b{}, depth {}): {} iterations, {} hits ({:.1}%), {:.1?} ({:.1}%)",
+                l.header,
+                l.depth,
+                hotspot.entries,
+                hotspot.hits,
+                percent(hotspot.hits),
+                hotspot.time,
+                time_percent(hotspot.time)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+    use crate::helpers::duet::tests::TestInstruction;
+    use crate::helpers::duet::Vm;
+
+    fn profile(program: &Program<TestInstruction>) -> Profile {
+        let mut vm = Vm::new(program.clone(), 0);
+        vm.start_profiling();
+        vm.execute().unwrap();
+        vm.stop_profiling().unwrap()
+    }
+
+    #[test]
+    fn test_profile() {
+        let program: Program<TestInstruction> =
+            "add a 2\nadd b 3\nadd b -1\njnz b -1\nadd a -1\njnz a -4"
+                .parse()
+                .unwrap();
+        let profile = profile(&program);
+
+        let hits = (0..program.len()).map(|ip| profile.hits(ip)).collect_vec();
+        assert_eq!(hits, [1, 2, 6, 6, 2, 2]);
+        assert_eq!(profile.jumps_taken(3), 4);
+        assert_eq!(profile.jumps_taken(5), 1);
+        assert_eq!(profile.total_hits(), 19);
+
+        let cfg = ControlFlowGraph::new(&program);
+        let blocks = profile.blocks(&cfg);
+        assert_eq!(blocks.iter().map(|block| block.entries).collect_vec(), [1, 2, 6, 2]);
+        assert_eq!(blocks.iter().map(|block| block.hits).collect_vec(), [1, 2, 12, 4]);
+
+        let loops = profile.hottest_loops(&cfg);
+        assert_eq!(loops.len(), 2);
+        assert_eq!((loops[0].0, loops[0].1.entries, loops[0].1.hits), (0, 2, 18));
+        assert_eq!((loops[1].0, loops[1].1.entries, loops[1].1.hits), (1, 6, 12));
+    }
+
+    #[test]
+    fn test_report() {
+        let program: Program<TestInstruction> = "add a 3\nadd a -1\njnz a -1".parse().unwrap();
+        let report = profile(&program).report(&program).to_string();
+        let lines = report.lines().collect_vec();
+
+        assert_eq!(lines[0], "1  14.3%  0: add a 3");
+        assert_eq!(lines[1], "3  42.9%  1: add a -1");
+        assert_eq!(lines[2], "3  42.9%  2: jnz a -1  ; taken 2/3 (66.7%)");
+        assert_eq!(lines[4], "blocks:");
+        assert!(lines[5].starts_with("    b0 (0-0): 1 entries, 1 hits, "));
+        assert!(lines[6].starts_with("    b1 (1-2): 3 entries, 6 hits, "));
+        assert_eq!(lines[8], "hottest loops:");
+        assert!(
+            lines[9].starts_with("    loop 0 (header b1, depth 0): 3 iterations, 6 hits (85.7%), ")
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, ensure, Context};
+use num::BigInt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::duet::{InstructionSet, Vm};
+
+/// Saved state of a [`Vm`], which can be serialized to pause a run and resume it later.
+///
+/// Only the state of the machine is saved: its step limit, arithmetic, infinite loop detection,
+/// recording and profiling settings are not, nor is its history (which is cleared on restore).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot<C> {
+    /// Source of the program, to make sure the snapshot is restored in the same program.
+    pub program: String,
+
+    /// Values of the registers, saturated if they don't fit in an `i64`.
+    pub registers: BTreeMap<String, i64>,
+
+    /// Exact values, in decimal, of the registers that don't fit in an `i64`
+    /// (with [`Arithmetic::BigInt`](crate::helpers::duet::Arithmetic::BigInt)).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub big_registers: BTreeMap<String, String>,
+
+    pub ip: i64,
+    pub steps: u64,
+    pub context: C,
+}
+
+/// Context of an [`InstructionSet`] whose state can be saved in a [`Snapshot`].
+pub trait SnapshotContext {
+    type Snapshot: Serialize + DeserializeOwned;
+
+    fn snapshot(&self) -> Self::Snapshot;
+
+    /// Replaces the context's state with the state saved in `snapshot`.
+    fn restore(&mut self, snapshot: Self::Snapshot) -> Result<(), anyhow::Error>;
+}
+
+impl<I> Vm<I>
+where
+    I: InstructionSet,
+    I::Context: SnapshotContext,
+{
+    pub fn snapshot(&self) -> Snapshot<<I::Context as SnapshotContext>::Snapshot> {
+        Snapshot {
+            program: self.program.to_string(),
+            registers: self
+                .program
+                .names
+                .iter()
+                .map(|register| (register.to_string(), self.registers.get(register)))
+                .collect(),
+            big_registers: self
+                .program
+                .names
+                .iter()
+                .filter(|register| self.registers.is_big(register))
+                .map(|register| {
+                    (register.to_string(), self.registers.get_big(register).to_string())
+                })
+                .collect(),
+            ip: self.ip,
+            steps: self.steps,
+            context: self.context.snapshot(),
+        }
+    }
+
+    /// Replaces the machine's state with the state saved in `snapshot`, which must have
+    /// been taken while running the same program.
+    pub fn restore(
+        &mut self,
+        snapshot: Snapshot<<I::Context as SnapshotContext>::Snapshot>,
+    ) -> Result<(), anyhow::Error> {
+        self.restore_state(snapshot)?;
+        self.forget_past();
+        Ok(())
+    }
+
+    /// Restores the state saved in `snapshot`, leaving the machine untouched if it is invalid.
+    /// Unlike [`Vm::restore`], the history and remembered states are kept.
+    pub(super) fn restore_state(
+        &mut self,
+        snapshot: Snapshot<<I::Context as SnapshotContext>::Snapshot>,
+    ) -> Result<(), anyhow::Error> {
+        ensure!(snapshot.program == self.program.to_string(), "snapshot of a different program");
+
+        let mut registers = self.registers.clone();
+        for (name, value) in snapshot.registers {
+            let Some(register) = self.program.names.get(&name) else {
+                bail!("unknown register in snapshot: {name}");
+            };
+            registers.set(&register, value);
+        }
+        for (name, value) in snapshot.big_registers {
+            let Some(register) = self.program.names.get(&name) else {
+                bail!("unknown register in snapshot: {name}");
+            };
+            let value = value
+                .parse::<BigInt>()
+                .with_context(|| format!("invalid value of register {name} in snapshot"))?;
+            registers.set_big(&register, value)?;
+        }
+        self.context
+            .restore(snapshot.context)
+            .context("invalid context in snapshot")?;
+
+        self.registers = registers;
+        self.ip = snapshot.ip;
+        self.steps = snapshot.steps;
+        self.set_externally(None);
+        Ok(())
+    }
+
+    /// Forgets the history and remembered states, which don't lead to a restored state.
+    pub(super) fn forget_past(&mut self) {
+        if let Some(seen_states) = &mut self.seen_states {
+            seen_states.clear();
+        }
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+    use crate::day_23::{ExperimentalCoprocessor, Instruction, InstructionDiscriminants, OpCounts};
+    use crate::helpers::duet::tests::TestInstruction;
+    use crate::helpers::duet::{Arithmetic, Program};
+    use crate::input::day_23::INPUT;
+
+    impl SnapshotContext for usize {
+        type Snapshot = usize;
+
+        fn snapshot(&self) -> usize {
+            *self
+        }
+
+        fn restore(&mut self, snapshot: usize) -> Result<(), anyhow::Error> {
+            *self = snapshot;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let program: Program<TestInstruction> =
+            "add a 100\nadd b a\nadd a -1\njnz a -2".parse().unwrap();
+        let mut vm = Vm::new(program.clone(), 0);
+        for _ in 0..50 {
+            vm.execute_next().unwrap();
+        }
+
+        let json = serde_json::to_string(&vm.snapshot()).unwrap();
+        assert!(json.contains(r#""registers":{"a":84,"b":1564}"#), "{json}");
+        vm.execute().unwrap();
+
+        let mut resumed = Vm::new(program, 0);
+        resumed
+            .restore(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        assert_eq!((resumed.ip(), resumed.steps(), *resumed.context()), (2, 50, 50));
+        resumed.execute().unwrap();
+        assert_eq!(resumed.registers(), vm.registers());
+        assert_eq!(resumed.steps(), vm.steps());
+        assert_eq!(resumed.context(), vm.context());
+    }
+
+    #[test]
+    fn test_restore_errors() {
+        let program: Program<TestInstruction> = "add a 1".parse().unwrap();
+        let snapshot = Vm::new(program.clone(), 0).snapshot();
+
+        let mut other = Vm::<TestInstruction>::new("add b 1".parse().unwrap(), 0);
+        let error = other.restore(snapshot.clone()).unwrap_err();
+        assert_eq!(error.to_string(), "snapshot of a different program");
+
+        let mut vm = Vm::new(program, 0);
+        let mut invalid = snapshot;
+        invalid.registers.insert("z".into(), 1);
+        let error = vm.restore(invalid).unwrap_err();
+        assert_eq!(error.to_string(), "unknown register in snapshot: z");
+    }
+
+    #[test]
+    fn test_big_registers() {
+        let program: Program<Instruction> = "set a 9223372036854775807\nmul a 4".parse().unwrap();
+        let mut coprocessor = ExperimentalCoprocessor::new(program.clone(), OpCounts::default());
+        coprocessor.set_arithmetic(Arithmetic::BigInt);
+        coprocessor.execute().unwrap();
+
+        let json = serde_json::to_string(&coprocessor.snapshot()).unwrap();
+        assert!(json.contains(r#""big_registers":{"a":"36893488147419103228"}"#), "{json}");
+
+        let mut checked = ExperimentalCoprocessor::new(program.clone(), OpCounts::default());
+        let error = checked
+            .restore(serde_json::from_str(&json).unwrap())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "value of register a is too large for Checked arithmetic");
+
+        let mut resumed = ExperimentalCoprocessor::new(program, OpCounts::default());
+        resumed.set_arithmetic(Arithmetic::BigInt);
+        resumed
+            .restore(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        assert_eq!(resumed.registers(), coprocessor.registers());
+    }
+
+    #[test]
+    fn test_op_counts() {
+        let program: Program<Instruction> = INPUT.parse().unwrap();
+        let mut coprocessor = ExperimentalCoprocessor::new(program.clone(), OpCounts::default());
+        for _ in 0..1000 {
+            coprocessor.execute_next().unwrap();
+        }
+
+        let json = serde_json::to_string(&coprocessor.snapshot()).unwrap();
+        assert!(
+            json.contains(
+                r#""context":{"dvt":0,"isp":0,"jgz":0,"jnz":249,"mad":0,"mul":124,"set":255,"sub":372}"#
+            ),
+            "{json}"
+        );
+        coprocessor.execute().unwrap();
+
+        let mut resumed = ExperimentalCoprocessor::new(program, OpCounts::default());
+        resumed
+            .restore(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        resumed.execute().unwrap();
+        assert_eq!(resumed.op_count(InstructionDiscriminants::Mul), 6_241);
+        assert_eq!(resumed.registers(), coprocessor.registers());
+    }
+}
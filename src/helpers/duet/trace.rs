@@ -0,0 +1,470 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::duet::InstructionResult;
+
+/// Step executed by a duet [`Vm`](crate::helpers::duet::Vm), as recorded in a trace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceStep {
+    /// ID of the program that executed the step.
+    pub program: usize,
+
+    /// Index of the step in the program's execution.
+    pub step: u64,
+
+    /// Instruction pointer before the step.
+    pub ip: i64,
+
+    pub instruction: String,
+    pub result: InstructionResult,
+
+    /// Registers modified by the step.
+    pub writes: Vec<RegisterWrite>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterWrite {
+    pub register: String,
+
+    /// Values before and after the step, saturated if they don't fit in an `i64`.
+    pub old: i64,
+    pub new: i64,
+
+    /// Exact values, in decimal, if they don't fit in an `i64`
+    /// (with [`Arithmetic::BigInt`](crate::helpers::duet::Arithmetic::BigInt)).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub big_old: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub big_new: Option<String>,
+}
+
+/// Values of registers set from outside a program, as recorded in a trace: all registers
+/// when recording starts, then registers set with
+/// [`Vm::set_register`](crate::helpers::duet::Vm::set_register) while recording.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterValues {
+    /// ID of the program whose registers were set.
+    pub program: usize,
+
+    /// Number of steps the program had executed when its registers were set.
+    pub step: u64,
+
+    /// Values of the registers, saturated if they don't fit in an `i64`.
+    pub registers: BTreeMap<String, i64>,
+
+    /// Exact values, in decimal, of the registers that don't fit in an `i64`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub big_registers: BTreeMap<String, String>,
+}
+
+/// Line of a trace in [JSON Lines](https://jsonlines.org/) format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum TraceEntry {
+    Step(TraceStep),
+    Registers(RegisterValues),
+}
+
+/// Destination of recorded [`TraceStep`]s.
+pub trait TraceSink: fmt::Debug {
+    fn record(&mut self, step: TraceStep) -> Result<(), anyhow::Error>;
+
+    /// Records registers set from outside the program. Ignored by default.
+    fn record_registers(&mut self, _registers: RegisterValues) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+impl TraceSink for Vec<TraceStep> {
+    fn record(&mut self, step: TraceStep) -> Result<(), anyhow::Error> {
+        self.push(step);
+        Ok(())
+    }
+}
+
+impl<S> TraceSink for Rc<RefCell<S>>
+where
+    S: TraceSink,
+{
+    fn record(&mut self, step: TraceStep) -> Result<(), anyhow::Error> {
+        self.borrow_mut().record(step)
+    }
+
+    fn record_registers(&mut self, registers: RegisterValues) -> Result<(), anyhow::Error> {
+        self.borrow_mut().record_registers(registers)
+    }
+}
+
+/// [`TraceSink`] writing steps in [JSON Lines](https://jsonlines.org/) format.
+#[derive(Debug)]
+pub struct JsonLines<W>(W);
+
+impl<W> JsonLines<W>
+where
+    W: Write,
+{
+    pub fn new(writer: W) -> Self {
+        Self(writer)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.0
+    }
+}
+
+impl<W> TraceSink for JsonLines<W>
+where
+    W: Write + fmt::Debug,
+{
+    fn record(&mut self, step: TraceStep) -> Result<(), anyhow::Error> {
+        self.write(&TraceEntry::Step(step))
+    }
+
+    fn record_registers(&mut self, registers: RegisterValues) -> Result<(), anyhow::Error> {
+        self.write(&TraceEntry::Registers(registers))
+    }
+}
+
+impl<W> JsonLines<W>
+where
+    W: Write,
+{
+    fn write(&mut self, entry: &TraceEntry) -> Result<(), anyhow::Error> {
+        serde_json::to_writer(&mut self.0, entry)?;
+        writeln!(self.0)?;
+        Ok(())
+    }
+}
+
+/// State of a program reconstructed from a trace.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProgramState {
+    /// Instruction pointer of the next instruction to execute.
+    pub ip: i64,
+
+    /// Number of steps executed.
+    pub steps: u64,
+
+    /// Values of registers recorded or modified so far, saturated if they don't fit in an `i64`.
+    /// Other registers are still at their initial value.
+    pub registers: BTreeMap<String, i64>,
+
+    /// Exact values, in decimal, of the registers that don't fit in an `i64`.
+    pub big_registers: BTreeMap<String, String>,
+
+    pub sent: Vec<i64>,
+    pub received: Vec<i64>,
+}
+
+impl ProgramState {
+    fn apply(&mut self, step: &TraceStep) {
+        self.ip = step.ip.saturating_add(step.result.jmp_offset());
+        self.steps = step.step + 1;
+        for write in &step.writes {
+            self.set(&write.register, write.new, write.big_new.as_ref());
+        }
+        self.sent.extend(step.result.sent());
+        self.received.extend(step.result.received());
+    }
+
+    fn set_registers(&mut self, registers: &RegisterValues) {
+        for (name, &value) in &registers.registers {
+            self.set(name, value, registers.big_registers.get(name));
+        }
+    }
+
+    fn set(&mut self, register: &str, value: i64, big: Option<&String>) {
+        self.registers.insert(register.to_string(), value);
+        match big {
+            Some(big) => self.big_registers.insert(register.to_string(), big.clone()),
+            None => self.big_registers.remove(register),
+        };
+    }
+}
+
+/// Trace read back from [JSON Lines](https://jsonlines.org/), used to reconstruct the state
+/// of the recorded programs at any step.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Replay {
+    steps: Vec<TraceStep>,
+
+    /// Registers set from outside the programs, along with the number of steps of the trace
+    /// preceding them.
+    registers: Vec<(usize, RegisterValues)>,
+}
+
+impl Replay {
+    pub fn new(steps: Vec<TraceStep>) -> Self {
+        Self { steps, registers: Vec::new() }
+    }
+
+    /// Reads a trace written by a [`JsonLines`] sink.
+    pub fn read<R>(reader: R) -> Result<Self, anyhow::Error>
+    where
+        R: BufRead,
+    {
+        let mut replay = Self::default();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line)
+                .with_context(|| format!("invalid trace step on line {}", i + 1))?
+            {
+                TraceEntry::Step(step) => replay.steps.push(step),
+                TraceEntry::Registers(registers) => {
+                    replay.registers.push((replay.steps.len(), registers));
+                },
+            }
+        }
+        Ok(replay)
+    }
+
+    pub fn steps(&self) -> &[TraceStep] {
+        &self.steps
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Returns the state of every program after the first `step` steps of the trace
+    /// have been executed.
+    pub fn state_at(&self, step: usize) -> BTreeMap<usize, ProgramState> {
+        let mut states = BTreeMap::new();
+        let mut registers = self.registers.iter().peekable();
+        for position in 0..=step.min(self.len()) {
+            while let Some((_, values)) = registers.next_if(|&&(at, _)| at == position) {
+                states
+                    .entry(values.program)
+                    .or_insert_with(ProgramState::default)
+                    .set_registers(values);
+            }
+            if let Some(trace_step) = self.steps.get(position).filter(|_| position < step) {
+                states
+                    .entry(trace_step.program)
+                    .or_insert_with(ProgramState::default)
+                    .apply(trace_step);
+            }
+        }
+        states
+    }
+
+    /// Returns the values sent by program `from` that have not yet been received by program `to`
+    /// after the first `step` steps, assuming `from` sends all its values to `to`.
+    pub fn pending_at(&self, step: usize, from: usize, to: usize) -> Vec<i64> {
+        let states = self.state_at(step);
+        let sent = states
+            .get(&from)
+            .map(|state| state.sent.as_slice())
+            .unwrap_or_default();
+        let received = states.get(&to).map_or(0, |state| state.received.len());
+        sent.iter().skip(received).copied().collect()
+    }
+
+    /// Returns the index of the first step that differs between this trace and another one,
+    /// or `None` if they are identical.
+    pub fn first_divergence(&self, other: &Self) -> Option<usize> {
+        self.steps
+            .iter()
+            .zip(&other.steps)
+            .position(|(a, b)| a != b)
+            .or_else(|| (self.len() != other.len()).then(|| self.len().min(other.len())))
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+    use crate::day_23::{ExperimentalCoprocessor, OpCounts};
+    use crate::helpers::duet::tests::TestInstruction;
+    use crate::helpers::duet::{Arithmetic, Vm};
+
+    fn trace(program: &str) -> Replay {
+        let sink = Rc::new(RefCell::new(JsonLines::new(Vec::new())));
+        let mut vm = Vm::<TestInstruction>::new(program.parse().unwrap(), 0);
+        vm.record(0, Rc::clone(&sink));
+        vm.execute().unwrap();
+        drop(vm);
+
+        let jsonl = Rc::into_inner(sink).unwrap().into_inner().into_inner();
+        Replay::read(jsonl.as_slice()).unwrap()
+    }
+
+    fn write(register: &str, old: i64, new: i64) -> RegisterWrite {
+        RegisterWrite { register: register.into(), old, new, big_old: None, big_new: None }
+    }
+
+    #[derive(Debug)]
+    struct FailingSink;
+
+    impl TraceSink for FailingSink {
+        fn record(&mut self, _: TraceStep) -> Result<(), anyhow::Error> {
+            Err(anyhow::anyhow!("sink failed"))
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let sink = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm::<TestInstruction>::new("add a 2\njnz a -1".parse().unwrap(), 0);
+        vm.record(3, Rc::clone(&sink));
+        vm.execute_next().unwrap();
+        vm.execute_next().unwrap();
+        vm.stop_recording();
+        vm.execute_next().unwrap();
+        assert_eq!(3, vm.steps());
+
+        let steps = sink.borrow().clone();
+        assert_eq!(
+            vec![
+                TraceStep {
+                    program: 3,
+                    step: 0,
+                    ip: 0,
+                    instruction: "add a 2".into(),
+                    result: InstructionResult::Unit,
+                    writes: vec![write("a", 0, 2)],
+                },
+                TraceStep {
+                    program: 3,
+                    step: 1,
+                    ip: 1,
+                    instruction: "jnz a -1".into(),
+                    result: InstructionResult::JmpOffset(-1),
+                    writes: Vec::new(),
+                },
+            ],
+            steps
+        );
+
+        let mut jsonl = JsonLines::new(Vec::new());
+        for step in &steps {
+            jsonl.record(step.clone()).unwrap();
+        }
+        let replay = Replay::read(jsonl.into_inner().as_slice()).unwrap();
+        assert_eq!(steps, replay.steps());
+    }
+
+    #[test]
+    fn test_record_error() {
+        let mut vm = Vm::<TestInstruction>::new("add a 2\njnz a -1".parse().unwrap(), 0);
+        vm.record(0, FailingSink);
+        assert_eq!("sink failed", vm.execute_next().unwrap_err().to_string());
+        assert_eq!(1, vm.ip());
+        assert_eq!(1, vm.steps());
+        assert_eq!(2, vm.register("a"));
+    }
+
+    #[test]
+    fn test_replay() {
+        let replay = trace("add a 2\nadd b 5\nadd a -1\njnz a -2");
+        assert_eq!(7, replay.len());
+        assert_eq!(
+            TraceStep {
+                program: 0,
+                step: 2,
+                ip: 2,
+                instruction: "add a -1".into(),
+                result: InstructionResult::Unit,
+                writes: vec![write("a", 2, 1)],
+            },
+            replay.steps()[2]
+        );
+        assert_eq!(InstructionResult::JmpOffset(-2), replay.steps()[3].result);
+
+        let state = &replay.state_at(4)[&0];
+        assert_eq!(1, state.ip);
+        assert_eq!(4, state.steps);
+        assert_eq!(BTreeMap::from([("a".into(), 1), ("b".into(), 5)]), state.registers);
+
+        let state = &replay.state_at(replay.len())[&0];
+        assert_eq!(4, state.ip);
+        assert_eq!(BTreeMap::from([("a".into(), 0), ("b".into(), 10)]), state.registers);
+        let state = &replay.state_at(0)[&0];
+        assert_eq!(0, state.steps);
+        assert_eq!(BTreeMap::from([("a".into(), 0), ("b".into(), 0)]), state.registers);
+    }
+
+    #[test]
+    fn test_external_registers() {
+        let sink = Rc::new(RefCell::new(JsonLines::new(Vec::new())));
+        let mut vm = Vm::<TestInstruction>::new("add a p\nadd b 1\nadd a p".parse().unwrap(), 0);
+        vm.set_register("p", 1);
+        vm.record(0, Rc::clone(&sink));
+        vm.execute_next().unwrap();
+        vm.set_register("p", 5);
+        vm.execute().unwrap();
+        drop(vm);
+
+        let jsonl = Rc::into_inner(sink).unwrap().into_inner().into_inner();
+        let replay = Replay::read(jsonl.as_slice()).unwrap();
+        assert_eq!(3, replay.len());
+        let registers = |step| replay.state_at(step)[&0].registers.clone();
+        let expected =
+            |a, b, p| BTreeMap::from([("a".into(), a), ("b".into(), b), ("p".into(), p)]);
+        assert_eq!(expected(0, 0, 1), registers(0));
+        assert_eq!(expected(1, 0, 5), registers(1));
+        assert_eq!(expected(1, 1, 5), registers(2));
+        assert_eq!(expected(6, 1, 5), registers(3));
+    }
+
+    #[test]
+    fn test_big_registers() {
+        let sink = Rc::new(RefCell::new(JsonLines::new(Vec::new())));
+        let program = "set a 9223372036854775807\nmul a 2\nsub a 1";
+        let mut coprocessor =
+            ExperimentalCoprocessor::new(program.parse().unwrap(), OpCounts::default());
+        coprocessor.set_arithmetic(Arithmetic::BigInt);
+        coprocessor.record(0, Rc::clone(&sink));
+        coprocessor.execute().unwrap();
+        drop(coprocessor);
+
+        let jsonl = Rc::into_inner(sink).unwrap().into_inner().into_inner();
+        let replay = Replay::read(jsonl.as_slice()).unwrap();
+        let write = &replay.steps()[1].writes[0];
+        assert_eq!((i64::MAX, i64::MAX), (write.old, write.new));
+        assert_eq!(None, write.big_old);
+        assert_eq!(Some("18446744073709551614".into()), write.big_new);
+        let write = &replay.steps()[2].writes[0];
+        assert_eq!(Some("18446744073709551614".into()), write.big_old);
+        assert_eq!(Some("18446744073709551613".into()), write.big_new);
+
+        let state = &replay.state_at(3)[&0];
+        assert_eq!(i64::MAX, state.registers["a"]);
+        assert_eq!(
+            BTreeMap::from([("a".into(), "18446744073709551613".into())]),
+            state.big_registers
+        );
+        assert!(replay.state_at(1)[&0].big_registers.is_empty());
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let replay = trace("add a 2\nadd b 5\nadd a -1\njnz a -2");
+        assert_eq!(None, replay.first_divergence(&replay));
+        assert_eq!(
+            Some(1),
+            replay.first_divergence(&trace("add a 2\nadd b 4\nadd a -1\njnz a -2"))
+        );
+        assert_eq!(Some(3), replay.first_divergence(&trace("add a 2\nadd b 5\nadd a -1")));
+    }
+
+    #[test]
+    fn test_read_invalid() {
+        let err = Replay::read("\n{}\n".as_bytes()).unwrap_err();
+        assert_eq!("invalid trace step on line 2", err.to_string());
+    }
+}
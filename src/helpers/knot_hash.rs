@@ -1,8 +1,9 @@
-use std::cell::RefCell;
-use std::fmt;
-use std::mem::swap;
-use std::ops::{BitXor, DerefMut};
+use std::hash::{BuildHasher, Hasher};
+use std::ops::BitXor;
+use std::str::FromStr;
+use std::{array, fmt, io};
 
+use anyhow::{anyhow, ensure};
 use itertools::Itertools;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -11,76 +12,335 @@ pub struct KnotHash {
 }
 
 impl KnotHash {
-    const EXTRA_LENGTHS: [u8; 5] = [17, 31, 73, 47, 23];
-
     pub fn new<I>(input: I) -> Self
     where
         I: AsRef<str>,
     {
-        let lengths = Self::lengths(input);
-        let sparse = Self::sparse_hash(lengths, 64);
-        let dense = Self::dense_hash(sparse);
+        Self::from_bytes(input.as_ref().as_bytes())
+    }
+
+    /// Computes the [`KnotHash`] of arbitrary bytes.
+    ///
+    /// To hash input that is not available all at once, use a [`KnotHasher`].
+    pub fn from_bytes<B>(input: B) -> Self
+    where
+        B: AsRef<[u8]>,
+    {
+        let mut hasher = KnotHasher::new();
+        hasher.update(input.as_ref());
+        hasher.finalize()
+    }
 
-        Self { dense }
+    /// Returns a [`KnotHashBuilder`] to compute knot hashes with non-standard parameters.
+    pub fn builder() -> KnotHashBuilder {
+        KnotHashBuilder::default()
     }
 
     pub fn dense(&self) -> &Vec<u8> {
         &self.dense
     }
 
+    /// Returns the lengths used to hash `input`: its bytes followed by the standard suffix.
+    #[deprecated(note = "use a `KnotHasher`, which appends the suffix itself")]
     pub fn lengths<I>(input: I) -> Vec<u8>
     where
         I: AsRef<str>,
     {
+        let params = KnotHashBuilder::default();
         input
             .as_ref()
             .as_bytes()
             .iter()
-            .chain(&Self::EXTRA_LENGTHS)
-            .cloned()
+            .chain(&params.suffix)
+            .copied()
             .collect_vec()
     }
 
+    /// Knots a ring of 256 numbers using `lengths` for the given number of `rounds`.
+    #[deprecated(note = "use `KnotHash::builder` with a block size of 1 and no suffix")]
     pub fn sparse_hash(lengths: Vec<u8>, rounds: usize) -> Vec<u8> {
-        let numbers = Self::initial_numbers();
+        KnotHash::builder().rounds(rounds).sparse_hash(&lengths)
+    }
 
-        let num_lengths = lengths.len() * rounds;
-        let _ = lengths
-            .iter()
-            .cycle()
-            .take(num_lengths)
-            .map(|&length| length as usize)
-            .fold((numbers.iter().cycle(), 0usize), |(numbers, skip), length| {
-                Self::swap_range(numbers.clone().take(length));
-                (numbers.dropping(length + skip), skip + 1)
-            });
+    /// XORs blocks of 16 numbers of a sparse hash together.
+    #[deprecated(note = "use `KnotHash::new` or a `KnotHasher`")]
+    pub fn dense_hash(sparse: Vec<u8>) -> Vec<u8> {
+        KnotHashBuilder::default().dense_hash(&sparse)
+    }
 
-        numbers.into_iter().map(RefCell::into_inner).collect()
+    /// Returns an iterator over the bits of the digest, most significant bit of each byte first.
+    pub fn bits(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.dense.len() * 8).map(|i| self.dense[i / 8] & (1 << (7 - i % 8)) != 0)
     }
 
-    pub fn dense_hash(sparse: Vec<u8>) -> Vec<u8> {
-        sparse
-            .into_iter()
-            .chunks(16)
-            .into_iter()
-            .map(|chunk| chunk.into_iter().reduce(BitXor::bitxor).unwrap())
-            .collect()
+    /// Returns the number of bits set in the digest.
+    pub fn count_ones(&self) -> u32 {
+        self.dense.iter().map(|n| n.count_ones()).sum()
+    }
+
+    /// Returns the number of bits that differ between this digest and another one,
+    /// or `None` if the two digests have different sizes.
+    pub fn hamming_distance(&self, other: &Self) -> Option<u32> {
+        (self.dense.len() == other.dense.len()).then(|| {
+            self.dense
+                .iter()
+                .zip(&other.dense)
+                .map(|(a, b)| (a ^ b).count_ones())
+                .sum()
+        })
+    }
+}
+
+impl FromStr for KnotHash {
+    type Err = anyhow::Error;
+
+    /// Parses a standard [`KnotHash`] from its 32-character hexadecimal representation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ensure!(
+            s.len() == 32 && s.bytes().all(|b| b.is_ascii_hexdigit()),
+            "invalid knot hash: {s}"
+        );
+
+        let dense = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect();
+        Ok(Self { dense })
+    }
+}
+
+impl From<[u8; 16]> for KnotHash {
+    fn from(value: [u8; 16]) -> Self {
+        Self { dense: value.to_vec() }
+    }
+}
+
+impl From<u128> for KnotHash {
+    fn from(value: u128) -> Self {
+        value.to_be_bytes().into()
+    }
+}
+
+impl TryFrom<&KnotHash> for [u8; 16] {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &KnotHash) -> Result<Self, Self::Error> {
+        value
+            .dense
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("knot hash is not 16 bytes long: {value}"))
+    }
+}
+
+impl TryFrom<&KnotHash> for u128 {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &KnotHash) -> Result<Self, Self::Error> {
+        Ok(u128::from_be_bytes(value.try_into()?))
     }
+}
+
+/// Parameters used to compute a [`KnotHash`].
+///
+/// The [default](KnotHashBuilder::default) parameters are the standard ones used
+/// in day 10 part 2: a ring of 256 numbers, 64 rounds, a suffix of `17, 31, 73, 47, 23`
+/// and dense blocks of 16 numbers.
+///
+/// A [`KnotHashBuilder`] can also be used as a [`BuildHasher`] to hash map keys with
+/// [`KnotHasher`]s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnotHashBuilder {
+    ring_size: usize,
+    rounds: usize,
+    suffix: Vec<u8>,
+    block_size: usize,
+}
 
-    fn initial_numbers() -> Vec<RefCell<u8>> {
-        (u8::MIN..=u8::MAX).map(RefCell::new).collect()
+impl KnotHashBuilder {
+    /// Sets the number of elements in the ring of numbers being knotted.
+    ///
+    /// # Panics
+    ///
+    /// If `ring_size` is not in the range `1..=256`.
+    pub fn ring_size(mut self, ring_size: usize) -> Self {
+        assert!((1..=256).contains(&ring_size), "invalid ring size: {ring_size}");
+        self.ring_size = ring_size;
+        self
     }
 
-    fn swap_range<'a, I, T>(range: I)
+    /// Sets the number of rounds performed over the input lengths.
+    pub fn rounds(mut self, rounds: usize) -> Self {
+        self.rounds = rounds;
+        self
+    }
+
+    /// Sets the lengths appended to the input before hashing.
+    pub fn suffix<S>(mut self, suffix: S) -> Self
     where
-        I: Iterator<Item = &'a RefCell<T>>,
-        T: 'a,
+        S: AsRef<[u8]>,
     {
-        let mut range = range.collect_vec().into_iter();
+        self.suffix = suffix.as_ref().to_vec();
+        self
+    }
+
+    /// Sets the number of numbers XORed together to produce each byte of the dense hash.
+    ///
+    /// # Panics
+    ///
+    /// If `block_size` is zero.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        assert_ne!(0, block_size, "invalid block size");
+        self.block_size = block_size;
+        self
+    }
+
+    /// Returns a [`KnotHasher`] using these parameters.
+    pub fn build(&self) -> KnotHasher {
+        KnotHasher { params: self.clone(), input: Vec::new() }
+    }
+
+    fn sparse_hash(&self, lengths: &[u8]) -> Vec<u8> {
+        let mut ring = Ring::new(self.ring_size);
+
+        for _ in 0..self.rounds {
+            lengths
+                .iter()
+                .for_each(|&length| ring.twist(length as usize));
+        }
+
+        ring.numbers[..self.ring_size].to_vec()
+    }
+
+    fn dense_hash(&self, sparse: &[u8]) -> Vec<u8> {
+        sparse
+            .chunks(self.block_size)
+            .map(|chunk| chunk.iter().copied().reduce(BitXor::bitxor).unwrap())
+            .collect()
+    }
+}
+
+impl Default for KnotHashBuilder {
+    fn default() -> Self {
+        Self { ring_size: 256, rounds: 64, suffix: vec![17, 31, 73, 47, 23], block_size: 16 }
+    }
+}
+
+impl BuildHasher for KnotHashBuilder {
+    type Hasher = KnotHasher;
 
-        while let (Some(a), Some(b)) = (range.next(), range.next_back()) {
-            swap(a.borrow_mut().deref_mut(), b.borrow_mut().deref_mut());
+    fn build_hasher(&self) -> Self::Hasher {
+        self.build()
+    }
+}
+
+/// Incremental builder of [`KnotHash`]es.
+///
+/// Bytes can be fed in chunks via [`update`](KnotHasher::update), [`Hasher::write`]
+/// or [`io::Write`] (e.g. with [`io::copy`] to hash file contents). Because every round
+/// of a knot hash replays the entire input, chunks are buffered until the hasher is
+/// [finalized](KnotHasher::finalize).
+///
+/// Through [`KnotHashBuilder`], knot hashes can also be used to hash map keys;
+/// [`Hasher::finish`] returns the first 8 bytes of the digest.
+#[derive(Debug, Default, Clone)]
+pub struct KnotHasher {
+    params: KnotHashBuilder,
+    input: Vec<u8>,
+}
+
+impl KnotHasher {
+    /// Returns a [`KnotHasher`] using the standard parameters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds a chunk of bytes to the hasher.
+    pub fn update(&mut self, bytes: &[u8]) -> &mut Self {
+        self.input.extend_from_slice(bytes);
+        self
+    }
+
+    /// Computes the [`KnotHash`] of all bytes fed so far.
+    ///
+    /// The hasher is not reset, so more bytes can be fed afterwards. Input lengths larger
+    /// than the ring size reverse the whole ring.
+    pub fn finalize(&self) -> KnotHash {
+        let lengths = self
+            .input
+            .iter()
+            .chain(&self.params.suffix)
+            .copied()
+            .collect_vec();
+        let sparse = self.params.sparse_hash(&lengths);
+        let dense = self.params.dense_hash(&sparse);
+
+        KnotHash { dense }
+    }
+
+    /// Clears all bytes fed so far.
+    pub fn reset(&mut self) {
+        self.input.clear();
+    }
+}
+
+impl Hasher for KnotHasher {
+    fn finish(&self) -> u64 {
+        self.finalize()
+            .dense
+            .iter()
+            .take(8)
+            .fold(0, |hash, &n| (hash << 8) | n as u64)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.update(bytes);
+    }
+}
+
+impl io::Write for KnotHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Circular list of numbers being knotted, along with the current position and skip size.
+///
+/// Only the first `size` numbers are part of the ring.
+#[derive(Debug, Clone)]
+struct Ring {
+    numbers: [u8; 256],
+    size: usize,
+    position: usize,
+    skip: usize,
+}
+
+impl Ring {
+    fn new(size: usize) -> Self {
+        Self { numbers: array::from_fn(|i| i as u8), size, position: 0, skip: 0 }
+    }
+
+    /// Reverses the order of `length` numbers starting at the current position
+    /// (wrapping around the end of the ring), then moves forward.
+    ///
+    /// The reversed span is clamped to the ring size, but the position still moves
+    /// forward by the full `length`.
+    fn twist(&mut self, length: usize) {
+        let span = length.min(self.size);
+
+        for i in 0..span / 2 {
+            let a = (self.position + i) % self.size;
+            let b = (self.position + span - 1 - i) % self.size;
+            self.numbers.swap(a, b);
         }
+
+        self.position = (self.position + length + self.skip) % self.size;
+        self.skip += 1;
     }
 }
 
@@ -89,3 +349,123 @@ impl fmt::Display for KnotHash {
         write!(f, "{}", self.dense.iter().map(|n| format!("{n:02x}")).join(""))
     }
 }
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    use super::*;
+
+    const EXAMPLES: &[(&str, &str)] = &[
+        ("", "a2582a3a0e66e6e86e3812dcb672a272"),
+        ("AoC 2017", "33efeb34ea91902bb2f59c9920caa6cd"),
+        ("1,2,3", "3efbe78a8d82f29979031a4aa0b16a9d"),
+        ("1,2,4", "63960835bcdc130f0b66d7ff4f6a5a8e"),
+    ];
+
+    #[test]
+    fn test_new() {
+        for &(input, expected) in EXAMPLES {
+            assert_eq!(expected, KnotHash::new(input).to_string());
+        }
+    }
+
+    #[test]
+    fn test_hasher() {
+        let mut hasher = KnotHasher::new();
+        hasher.update(b"AoC").update(b" 20");
+        hasher.write_all(b"17").unwrap();
+        assert_eq!("33efeb34ea91902bb2f59c9920caa6cd", hasher.finalize().to_string());
+        assert_eq!(0x33efeb34ea91902b, hasher.finish());
+
+        hasher.reset();
+        assert_eq!("a2582a3a0e66e6e86e3812dcb672a272", hasher.finalize().to_string());
+    }
+
+    #[test]
+    fn test_build_hasher() {
+        let mut map = HashMap::with_hasher(KnotHashBuilder::default());
+        map.insert("AoC 2017", 1);
+        map.insert("1,2,3", 2);
+        assert_eq!(Some(&1), map.get("AoC 2017"));
+        assert_eq!(Some(&2), map.get("1,2,3"));
+        assert_eq!(None, map.get("1,2,4"));
+    }
+
+    #[test]
+    fn test_digest() {
+        let hash = KnotHash::new("AoC 2017");
+        assert_eq!(hash, "33efeb34ea91902bb2f59c9920caa6cd".parse().unwrap());
+        assert!("33efeb34ea91902bb2f59c9920caa6c"
+            .parse::<KnotHash>()
+            .is_err());
+        assert!("33efeb34ea91902bb2f59c9920caa6cg"
+            .parse::<KnotHash>()
+            .is_err());
+        assert!("+3efeb34ea91902bb2f59c9920caa6cd"
+            .parse::<KnotHash>()
+            .is_err());
+        assert!("3éfeb34ea91902bb2f59c9920caa6cd"
+            .parse::<KnotHash>()
+            .is_err());
+
+        let n = u128::try_from(&hash).unwrap();
+        assert_eq!(0x33efeb34ea91902bb2f59c9920caa6cd, n);
+        assert_eq!(hash, KnotHash::from(n));
+        assert_eq!(0x33, <[u8; 16]>::try_from(&hash).unwrap()[0]);
+
+        assert_eq!(
+            vec![false, false, true, true, false, false, true, true, true, true, true, false],
+            hash.bits().take(12).collect_vec()
+        );
+        assert_eq!(n.count_ones(), hash.count_ones());
+        assert_eq!(n.count_ones(), hash.bits().filter(|&b| b).count() as u32);
+
+        let other = KnotHash::new("1,2,3");
+        assert_eq!(Some(0), hash.hamming_distance(&hash));
+        assert_eq!(
+            Some((n ^ u128::try_from(&other).unwrap()).count_ones()),
+            hash.hamming_distance(&other)
+        );
+
+        let smaller = KnotHash::builder().block_size(32).build().finalize();
+        assert_eq!(None, hash.hamming_distance(&smaller));
+    }
+
+    #[test]
+    fn test_builder() {
+        let hash = KnotHash::builder()
+            .ring_size(5)
+            .rounds(1)
+            .suffix([])
+            .block_size(1)
+            .build()
+            .update(&[3, 4, 1, 5])
+            .finalize();
+        assert_eq!(&vec![3, 4, 2, 1, 0], hash.dense());
+
+        assert_eq!(
+            KnotHash::new("AoC 2017"),
+            KnotHash::builder().build().update(b"AoC 2017").finalize()
+        );
+
+        let mut hasher = KnotHash::builder().ring_size(5).build();
+        hasher.update(&[3, 200, 1]);
+        assert_eq!(1, hasher.finalize().dense().len());
+        assert_eq!(hasher.finalize().dense()[0] as u64, hasher.finish());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated() {
+        let lengths = KnotHash::lengths("AoC 2017");
+        assert_eq!(b"AoC", &lengths[..3]);
+        assert_eq!(&[17, 31, 73, 47, 23], &lengths[lengths.len() - 5..]);
+
+        let sparse = KnotHash::sparse_hash(lengths, 64);
+        assert_eq!(256, sparse.len());
+        assert_eq!(KnotHash::new("AoC 2017").dense(), &KnotHash::dense_hash(sparse));
+    }
+}
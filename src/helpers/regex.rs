@@ -1,11 +1,45 @@
 use std::str::FromStr;
 
-use regex::Captures;
+use anyhow::{anyhow, Context};
+use regex::{Captures, Regex};
 
 pub trait CapturesHelper {
+    /// Parses the value of the named capture group.
+    ///
+    /// # Panics
+    ///
+    /// If the group did not participate in the match or if its value cannot be parsed.
+    /// See [`try_get`](CapturesHelper::try_get) for a fallible version.
     fn ez_get<T>(&self, name: &str) -> T
     where
         T: FromStr;
+
+    /// Parses the value of the named capture group, returning an error if the
+    /// group did not participate in the match or if its value cannot be parsed.
+    fn try_get<T>(&self, name: &str) -> Result<T, anyhow::Error>
+    where
+        T: FromStr;
+
+    /// Parses the value of an optional named capture group.
+    ///
+    /// Returns `Ok(None)` if the group did not participate in the match, and an error
+    /// if it did but its value cannot be parsed.
+    fn get_opt<T>(&self, name: &str) -> Result<Option<T>, anyhow::Error>
+    where
+        T: FromStr;
+
+    /// Parses every match of `item` found in the value of the named capture group.
+    ///
+    /// This is useful for repeated sub-patterns like comma-separated lists, since a
+    /// capture group only keeps its last repetition. If the group did not participate
+    /// in the match, an empty [`Vec`] is returned.
+    ///
+    /// Text between matches (like separators) is skipped without being checked, so `"1,x,2"`
+    /// yields `[1, 2]` for an `item` of `\d+`. Validate the group's format in the enclosing
+    /// pattern (e.g. `(?<list>\d+(?:, \d+)*)`) when malformed input must be rejected.
+    fn get_all<T>(&self, name: &str, item: &Regex) -> Result<Vec<T>, anyhow::Error>
+    where
+        T: FromStr;
 }
 
 impl<'h> CapturesHelper for Captures<'h> {
@@ -13,8 +47,106 @@ impl<'h> CapturesHelper for Captures<'h> {
     where
         T: FromStr,
     {
-        self[name]
-            .parse::<T>()
-            .unwrap_or_else(|_| panic!("invalid value for {name}: {}", &self[name]))
+        self.try_get(name).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    fn try_get<T>(&self, name: &str) -> Result<T, anyhow::Error>
+    where
+        T: FromStr,
+    {
+        self.get_opt(name)?
+            .with_context(|| format!("missing value for {name}"))
+    }
+
+    fn get_opt<T>(&self, name: &str) -> Result<Option<T>, anyhow::Error>
+    where
+        T: FromStr,
+    {
+        self.name(name)
+            .map(|m| parse_value(name, m.as_str()))
+            .transpose()
+    }
+
+    fn get_all<T>(&self, name: &str, item: &Regex) -> Result<Vec<T>, anyhow::Error>
+    where
+        T: FromStr,
+    {
+        self.name(name)
+            .map(|m| {
+                item.find_iter(m.as_str())
+                    .map(|im| parse_value(name, im.as_str()))
+                    .collect()
+            })
+            .unwrap_or_else(|| Ok(Vec::new()))
+    }
+}
+
+fn parse_value<T>(name: &str, value: &str) -> Result<T, anyhow::Error>
+where
+    T: FromStr,
+{
+    value
+        .parse()
+        .map_err(|_| anyhow!("invalid value for {name}: {value}"))
+}
+
+#[cfg(test)]
+#[cfg(feature = "utils")]
+mod tests {
+    use super::*;
+
+    fn regex() -> Regex {
+        Regex::new(r"^(?<id>\d+)(?: \((?<weight>\w+)\))?(?: -> (?<children>.+))?$").unwrap()
+    }
+
+    #[test]
+    fn test_try_get() {
+        let re = regex();
+
+        let captures = re.captures("42 (7)").unwrap();
+        assert_eq!(42, captures.try_get::<usize>("id").unwrap());
+        assert_eq!(7, captures.try_get::<usize>("weight").unwrap());
+        assert!(captures.try_get::<usize>("children").is_err());
+
+        let captures = re.captures("42 (abc)").unwrap();
+        assert!(captures.try_get::<usize>("weight").is_err());
+    }
+
+    #[test]
+    fn test_get_opt() {
+        let re = regex();
+
+        let captures = re.captures("42").unwrap();
+        assert_eq!(None, captures.get_opt::<usize>("weight").unwrap());
+
+        let captures = re.captures("42 (7)").unwrap();
+        assert_eq!(Some(7), captures.get_opt::<usize>("weight").unwrap());
+
+        let captures = re.captures("42 (abc)").unwrap();
+        assert!(captures.get_opt::<usize>("weight").is_err());
+    }
+
+    #[test]
+    fn test_get_all() {
+        let re = regex();
+        let item = Regex::new(r"\d+").unwrap();
+
+        let captures = re.captures("42 -> 1, 2, 3").unwrap();
+        assert_eq!(vec![1, 2, 3], captures.get_all::<usize>("children", &item).unwrap());
+
+        let captures = re.captures("42").unwrap();
+        assert!(captures
+            .get_all::<usize>("children", &item)
+            .unwrap()
+            .is_empty());
+
+        let item = Regex::new(r"\w+").unwrap();
+        let captures = re.captures("42 -> 1, x").unwrap();
+        assert!(captures.get_all::<usize>("children", &item).is_err());
+
+        // Text between matches is skipped.
+        let captures = re.captures("42 -> 1,x,2").unwrap();
+        let item = Regex::new(r"\d+").unwrap();
+        assert_eq!(vec![1, 2], captures.get_all::<usize>("children", &item).unwrap());
     }
 }
@@ -29,3 +29,4 @@ pub mod day_24;
 pub mod day_25;
 pub(crate) mod helpers;
 pub(crate) mod input;
+pub mod tools;
@@ -0,0 +1,261 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
+use anyhow::{bail, Context};
+
+use crate::day_18::DuetInterpreter;
+use crate::day_23::{ExperimentalCoprocessor, OpCounts};
+use crate::helpers::duet::cfg::ControlFlowGraph;
+use crate::helpers::duet::debugger::Debugger;
+use crate::helpers::duet::decompiler::Decompile;
+use crate::helpers::duet::history::Reversible;
+use crate::helpers::duet::lint::Lint;
+use crate::helpers::duet::network::{NetworkBuilder, Ports};
+use crate::helpers::duet::trace::JsonLines;
+use crate::helpers::duet::{InstructionSet, Program, Vm};
+use crate::{day_18, day_23, input};
+
+const USAGE: &str = "\
+usage: duet <command> <day18|day23> [--looped] [program file]
+
+Runs a tool on a duet program. If no program file is specified, the puzzle input is used.
+Programs are stopped after 1000000 steps, or as soon as they are caught in an infinite loop.
+
+commands:
+    list       show an annotated listing of the program
+    disasm     show the program's source, with labels for jump targets
+    decompile  show the program as structured pseudocode, with loops and conditionals
+    idioms     show the program's source, with known loop idioms replaced by non-standard
+               superinstructions (day23 only)
+    cfg        show the program's control-flow graph, in Graphviz DOT format
+    lint       check the program for likely mistakes (registers p for day18 and a for day23
+               are assumed to be initialized)
+    debug      start an interactive debugging session, which can also step backwards
+               (for day18, --looped debugs a part 2 program sending values to itself, so that
+               its queue can be inspected with the context command)
+    run        run the program, then show how it stopped (for day18, both programs of part 2
+               are run, each on its own thread)
+    trace      run the program, printing a trace of every executed step as JSON Lines
+               (for day18, both programs of part 2 are traced as programs 0 and 1)
+    profile    run the program, then show how often each instruction was executed,
+               the time spent in each basic block and the hottest loops
+               (for day18, both programs of part 2 are profiled)";
+
+/// Maximum number of steps a program can execute when run by the tools.
+const STEP_LIMIT: u64 = 1_000_000;
+
+/// Entry point of the `duet` command-line tool.
+pub fn run<A, R, W>(args: A, input: R, mut output: W) -> Result<(), anyhow::Error>
+where
+    A: IntoIterator<Item = String>,
+    R: BufRead,
+    W: Write,
+{
+    let mut args: Vec<_> = args.into_iter().collect();
+    let looped = args.iter().any(|arg| arg == "--looped");
+    args.retain(|arg| arg != "--looped");
+    let (command, day, file) = match args.as_slice() {
+        [command, day] => (command.as_str(), day.as_str(), None),
+        [command, day, file] => (command.as_str(), day.as_str(), Some(file)),
+        _ => {
+            writeln!(output, "{USAGE}")?;
+            return Ok(());
+        },
+    };
+
+    let text = match (day, file) {
+        (_, Some(file)) => {
+            fs::read_to_string(file).with_context(|| format!("failed to read {file}"))?
+        },
+        ("day18", None) => input::day_18::INPUT.into(),
+        ("day23", None) => input::day_23::INPUT.into(),
+        (day, None) => bail!("invalid day: {day}"),
+    };
+
+    if looped && (command, day) != ("debug", "day18") {
+        bail!("--looped is only supported by debug day18");
+    }
+
+    match (command, day) {
+        ("list", "day18") => list::<day_18::Instruction, _>(&text, output),
+        ("list", "day23") => list::<day_23::Instruction, _>(&text, output),
+        ("disasm", "day18") => disassemble::<day_18::Instruction, _>(&text, output),
+        ("disasm", "day23") => disassemble::<day_23::Instruction, _>(&text, output),
+        ("decompile", "day18") => decompile::<day_18::Instruction, _>(&text, output),
+        ("decompile", "day23") => decompile::<day_23::Instruction, _>(&text, output),
+        ("idioms", "day23") => {
+            let program: Program<day_23::Instruction> = text.parse()?;
+            write!(output, "{}", program.replace_idioms().disassemble())?;
+            Ok(())
+        },
+        ("idioms", "day18") => bail!("no loop idioms are known for day18"),
+        ("cfg", "day18") => control_flow_graph::<day_18::Instruction, _>(&text, output),
+        ("cfg", "day23") => control_flow_graph::<day_23::Instruction, _>(&text, output),
+        ("lint", "day18") => lint::<day_18::Instruction, _>(&text, &["p"], output),
+        ("lint", "day23") => lint::<day_23::Instruction, _>(&text, &["a"], output),
+        ("debug", "day18") if looped => {
+            debug(DuetInterpreter::networked(text.parse()?, Ports::looped()), input, output)
+        },
+        ("debug", "day18") => debug(DuetInterpreter::for_part_1(text.parse()?), input, output),
+        ("debug", "day23") => {
+            debug(ExperimentalCoprocessor::new(text.parse()?, OpCounts::default()), input, output)
+        },
+        ("run", "day18") => {
+            let program: Program<day_18::Instruction> = text.parse()?;
+            let report = NetworkBuilder::new(2).run_threaded(|ports| {
+                guarded(DuetInterpreter::networked(program.clone(), ports))
+            })?;
+            write!(output, "{report}")?;
+            Ok(())
+        },
+        ("run", "day23") => {
+            let mut coprocessor =
+                guarded(ExperimentalCoprocessor::new(text.parse()?, OpCounts::default()));
+            coprocessor.execute()?;
+            writeln!(output, "exited after {} steps", coprocessor.steps())?;
+            writeln!(output, "{}", coprocessor.context())?;
+            Ok(())
+        },
+        ("trace", "day18") => {
+            let sink = Rc::new(RefCell::new(JsonLines::new(Vec::new())));
+            let program: Program<day_18::Instruction> = text.parse()?;
+            let mut network = NetworkBuilder::new(2).build(|ports| {
+                let id = ports.id();
+                let mut interpreter = guarded(DuetInterpreter::networked(program.clone(), ports));
+                interpreter.record(id, Rc::clone(&sink));
+                interpreter
+            })?;
+            let result = network.run().map(|_| ());
+            drop(network);
+            write_trace(sink, output)?;
+            result
+        },
+        ("trace", "day23") => {
+            let sink = Rc::new(RefCell::new(JsonLines::new(Vec::new())));
+            let mut coprocessor =
+                guarded(ExperimentalCoprocessor::new(text.parse()?, OpCounts::default()));
+            coprocessor.record(0, Rc::clone(&sink));
+            let result = coprocessor.execute().map(|_| ());
+            drop(coprocessor);
+            write_trace(sink, output)?;
+            result
+        },
+        ("profile", "day18") => {
+            let program: Program<day_18::Instruction> = text.parse()?;
+            let mut network = NetworkBuilder::new(2).build(|ports| {
+                let mut interpreter = guarded(DuetInterpreter::networked(program.clone(), ports));
+                interpreter.start_profiling();
+                interpreter
+            })?;
+            network.run()?;
+            for (id, interpreter) in network.programs().iter().enumerate() {
+                let profile = interpreter.profile().expect("program should be profiled");
+                writeln!(output, "program {id}:\n{}", profile.report(&program))?;
+            }
+            Ok(())
+        },
+        ("profile", "day23") => {
+            let program: Program<day_23::Instruction> = text.parse()?;
+            let mut coprocessor =
+                guarded(ExperimentalCoprocessor::new(program.clone(), OpCounts::default()));
+            coprocessor.start_profiling();
+            coprocessor.execute()?;
+            let profile = coprocessor.profile().expect("program should be profiled");
+            write!(output, "{}", profile.report(&program))?;
+            Ok(())
+        },
+        (_, "day18" | "day23") => bail!("invalid command: {command}"),
+        (_, day) => bail!("invalid day: {day}"),
+    }
+}
+
+/// Makes sure programs run by the tools eventually stop, even if they never exit.
+fn guarded<I>(mut vm: Vm<I>) -> Vm<I>
+where
+    I: InstructionSet,
+{
+    vm.set_step_limit(Some(STEP_LIMIT));
+    vm.detect_infinite_loops(true);
+    vm
+}
+
+/// Debugs `vm`, recording its history so that the session can step backwards. The step limit
+/// also bounds the size of the history.
+fn debug<I, R, W>(vm: Vm<I>, input: R, output: W) -> Result<(), anyhow::Error>
+where
+    I: Reversible,
+    I::Context: fmt::Display,
+    R: BufRead,
+    W: Write,
+{
+    let mut vm = guarded(vm);
+    vm.record_history(None);
+    Debugger::new(vm).repl(input, output)
+}
+
+fn list<I, W>(text: &str, mut output: W) -> Result<(), anyhow::Error>
+where
+    I: InstructionSet + fmt::Display,
+    W: Write,
+{
+    let program: Program<I> = text.parse()?;
+    write!(output, "{}", program.listing())?;
+    Ok(())
+}
+
+fn write_trace<W>(sink: Rc<RefCell<JsonLines<Vec<u8>>>>, mut output: W) -> Result<(), anyhow::Error>
+where
+    W: Write,
+{
+    let trace = Rc::into_inner(sink)
+        .expect("trace sink still in use")
+        .into_inner()
+        .into_inner();
+    output.write_all(&trace)?;
+    Ok(())
+}
+
+fn disassemble<I, W>(text: &str, mut output: W) -> Result<(), anyhow::Error>
+where
+    I: InstructionSet,
+    W: Write,
+{
+    let program: Program<I> = text.parse()?;
+    write!(output, "{}", program.disassemble())?;
+    Ok(())
+}
+
+fn decompile<I, W>(text: &str, mut output: W) -> Result<(), anyhow::Error>
+where
+    I: Decompile,
+    W: Write,
+{
+    let program: Program<I> = text.parse()?;
+    write!(output, "{}", program.decompile())?;
+    Ok(())
+}
+
+fn control_flow_graph<I, W>(text: &str, mut output: W) -> Result<(), anyhow::Error>
+where
+    I: InstructionSet,
+    W: Write,
+{
+    let program: Program<I> = text.parse()?;
+    write!(output, "{}", ControlFlowGraph::new(&program).dot())?;
+    Ok(())
+}
+
+fn lint<I, W>(text: &str, inputs: &[&str], mut output: W) -> Result<(), anyhow::Error>
+where
+    I: Lint,
+    W: Write,
+{
+    let program: Program<I> = text.parse()?;
+    for diagnostic in program.lint(inputs) {
+        writeln!(output, "{diagnostic}")?;
+    }
+    Ok(())
+}